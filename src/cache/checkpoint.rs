@@ -0,0 +1,103 @@
+//! Journal of the files processed by a `cache` run, so an interrupted run can be resumed
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use assembly_pack::{
+    common::{FileMeta, FileMetaPair},
+    txt::FileLine,
+};
+
+use super::sd0::Settings;
+
+/// How often the journal is flushed to disk
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// A file that was processed by an interrupted run
+pub(super) struct Entry {
+    pub mtime: f64,
+    pub line: FileLine,
+}
+
+fn parse_meta<'a>(fields: &mut impl Iterator<Item = &'a str>) -> Option<FileMeta> {
+    let size = fields.next()?.parse().ok()?;
+    let hash = fields.next()?.parse().ok()?;
+    Some(FileMeta { size, hash })
+}
+
+/// Parse a `path,mtime,size,hash,compressed_size,compressed_hash,linesum` line
+fn parse_line(line: &str) -> Option<(String, Entry)> {
+    let mut fields = line.trim_end().split(',');
+    let path = fields.next()?.to_owned();
+    let mtime = fields.next()?.parse().ok()?;
+    let raw = parse_meta(&mut fields)?;
+    let compressed = parse_meta(&mut fields)?;
+    let linesum = fields.next()?.parse().ok()?;
+    let line = (FileMetaPair { raw, compressed }, linesum);
+    Some((path, Entry { mtime, line }))
+}
+
+/// Load the journal of an interrupted run with the same sd0 settings
+pub(super) fn load(path: &Path, settings: &Settings) -> io::Result<BTreeMap<String, Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = BufReader::new(file).lines();
+    match lines.next().transpose()? {
+        Some(header) if Settings::parse(&header).as_ref() == Some(settings) => {}
+        _ => {
+            log::info!("Ignoring checkpoint {} with other settings", path.display());
+            return Ok(BTreeMap::new());
+        }
+    }
+    let mut entries = BTreeMap::new();
+    for line in lines {
+        // The last line may be cut off
+        if let Some((path, entry)) = parse_line(&line?) {
+            entries.insert(path, entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// The journal of the current run
+pub(super) struct Checkpoint {
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+impl Checkpoint {
+    /// Start a new journal, or continue the one that was loaded if `resume` is set
+    pub fn open(path: &Path, settings: &Settings, resume: bool) -> io::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        if !resume {
+            writeln!(writer, "{}", settings)?;
+        }
+        Ok(Self {
+            writer,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Record a processed file, flushing the journal every few seconds
+    pub fn write(&mut self, path: &str, mtime: f64, line: &FileLine) -> io::Result<()> {
+        writeln!(self.writer, "{},{},{},{}", path, mtime, line.0, line.1)?;
+        if self.last_flush.elapsed() > INTERVAL {
+            self.writer.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
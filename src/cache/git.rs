@@ -0,0 +1,45 @@
+use std::{path::Path, process::Command};
+
+use color_eyre::eyre::{eyre, Context};
+
+/// Run git in `dir` and split its `-z` output into paths
+fn git_paths(dir: &Path, args: &[&str]) -> color_eyre::Result<Vec<String>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("git {} failed: {}", args.join(" "), stderr.trim()));
+    }
+    let paths = output
+        .stdout
+        .split(|b| *b == 0)
+        .filter(|p| !p.is_empty())
+        .map(|p| String::from_utf8_lossy(p).into_owned())
+        .collect();
+    Ok(paths)
+}
+
+/// Get all files below `dir` that were added, modified or deleted since `rev`
+///
+/// This includes uncommitted changes and untracked files, the paths are relative to `dir`.
+pub(super) fn changed_files(dir: &Path, rev: &str) -> color_eyre::Result<Vec<String>> {
+    let diff = [
+        "diff",
+        "--name-only",
+        "--no-renames",
+        "--relative",
+        "-z",
+        rev,
+        "--",
+    ];
+    let mut files = git_paths(dir, &diff)?;
+    let untracked = ["ls-files", "--others", "--exclude-standard", "-z", "--"];
+    files.extend(git_paths(dir, &untracked)?);
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
@@ -1,12 +1,12 @@
 use std::{
     fs::File,
     io::{self, BufWriter, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use assembly_pack::txt::Manifest;
+use assembly_pack::{crc::calculate_crc, pki::core::PackIndexFile, txt::Manifest};
 
-pub(super) fn write_manifest(manifest: Manifest, path: &Path) -> io::Result<()> {
+pub(crate) fn write_manifest(manifest: &Manifest, path: &Path) -> io::Result<()> {
     let mf_file = File::create(path)?;
     let mut mf_writer = BufWriter::new(mf_file);
 
@@ -14,9 +14,45 @@ pub(super) fn write_manifest(manifest: Manifest, path: &Path) -> io::Result<()>
     writeln!(mf_writer, "[version]")?;
     writeln!(mf_writer, "{}", &manifest.version)?;
     writeln!(mf_writer, "[files]")?;
-    for (k, (v, s)) in manifest.files {
+    for (k, (v, s)) in &manifest.files {
         writeln!(mf_writer, "{},{},{}", k, v, s)?;
     }
 
     Ok(())
 }
+
+/// The copy of the manifest at `path` for `version` in the history dir
+pub(crate) fn history_path(dir: &Path, path: &Path, version: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or(path.as_os_str());
+    dir.join(format!("{}.{}.txt", stem.to_string_lossy(), version))
+}
+
+/// The manifest without the files that are packed according to the PKI
+///
+/// Also returns the archives of the PKI that are not in the manifest.
+pub(super) fn packed_manifest<'a>(
+    manifest: &Manifest,
+    pack_index: &'a PackIndexFile,
+) -> (Manifest, Vec<&'a str>) {
+    let files = manifest
+        .files
+        .iter()
+        .filter(|(name, _)| {
+            !pack_index
+                .files
+                .contains_key(&calculate_crc(name.as_bytes()))
+        })
+        .map(|(name, line)| (name.clone(), *line))
+        .collect();
+    let packed = Manifest {
+        version: manifest.version.clone(),
+        files,
+    };
+    let missing = pack_index
+        .archives
+        .iter()
+        .map(|archive| archive.path.as_str())
+        .filter(|path| !manifest.files.contains_key(*path))
+        .collect();
+    (packed, missing)
+}
@@ -1,59 +1,105 @@
 use argh::FromArgs;
 use assembly_pack::{
-    common::{
-        fs::{scan_dir, FileInfo, FsVisitor},
-        FileMetaPair,
-    },
+    common::fs::{scan_dir, FileInfo, FsVisitor},
     crc::calculate_crc,
     md5::{self, MD5Sum},
-    sd0::fs::Converter,
+    pki::core::PackIndexFile,
     txt::{FileLine, Manifest, VersionLine},
 };
-use color_eyre::eyre::Context;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Help,
+};
 use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{HumanBytes, ProgressBar};
 use std::{
-    collections::BTreeMap,
+    collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet},
     fs::{File, Metadata},
-    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
     path::{Path, PathBuf},
     time::{Duration, UNIX_EPOCH},
 };
 
-use crate::{cache::quickcheck::scan_quickcheck, config::ProjectConfig, Paths, ProjectArgs};
+use crate::{
+    cache::quickcheck::scan_quickcheck, config::ProjectConfig, lowercase_path, progress, Paths,
+    ProjectArgs,
+};
 
-use self::quickcheck::QuickCheck;
+use self::{checkpoint::Checkpoint, quickcheck::QuickCheck, sd0::Origin, validate::Problem};
 
+mod checkpoint;
+mod git;
 mod manifest;
 mod quickcheck;
+mod sd0;
+mod validate;
+mod watch;
 
-#[derive(FromArgs, PartialEq, Debug)]
+pub(crate) use self::{
+    manifest::{history_path, write_manifest},
+    sd0::{Converter, Policies, Settings},
+};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
 #[argh(subcommand, name = "cache")]
 /// scans a file tree, generating sd0 compressed files and a manifest file
 pub struct Args {
     /// version number
     #[argh(option, short = 'v', default = "1")]
-    version: u32,
+    pub version: u32,
 
     /// version name
     #[argh(option, short = 'n')]
-    name: Option<String>,
+    pub name: Option<String>,
 
     /// don't ignore pk files
     #[argh(switch, short = 'i')]
-    include_pk: bool,
+    pub include_pk: bool,
 
     /// assume the filenames passed to -F / --files are already relative to `dir`
     ///
     /// i.e. don't strip the prefix (e.g. client\)
     #[argh(switch, short = 'r')]
-    relative: bool,
+    pub relative: bool,
 
     /// name of a file containing one path per line
     #[argh(option, short = 'F')]
-    files: Option<PathBuf>,
+    pub files: Option<PathBuf>,
+
+    /// only scan files that changed since the given git revision
+    #[argh(option)]
+    pub since: Option<String>,
+
+    /// keep running and update the cache when files in the project change
+    #[argh(switch, short = 'w')]
+    pub watch: bool,
+
+    /// the zlib compression level (0-9) of the sd0 files, overrides the project config
+    #[argh(option)]
+    pub level: Option<u32>,
+
+    /// generate `si0` segment index files next to the sd0 files
+    #[argh(switch)]
+    pub segment_index: bool,
 }
 
-fn hash_to_path(hash: &MD5Sum) -> String {
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            version: 1,
+            name: None,
+            include_pk: false,
+            relative: false,
+            files: None,
+            since: None,
+            watch: false,
+            level: None,
+            segment_index: false,
+        }
+    }
+}
+
+pub(crate) fn hash_to_path(hash: &MD5Sum) -> String {
     const SEP: char = std::path::MAIN_SEPARATOR;
     let hash = format!("{:?}", hash);
     let mut chars = hash.chars();
@@ -62,13 +108,25 @@ fn hash_to_path(hash: &MD5Sum) -> String {
     format!("{}{SEP}{}{SEP}{}.sd0", c1, c2, hash)
 }
 
-#[derive(Default, Debug)]
-struct Stats {
-    quickcheck: usize,
-    compress: usize,
-    updated: usize,
-    total: usize,
-    ignored: usize,
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+/// The result of a [`run`]
+pub struct Stats {
+    /// Files that were unchanged according to the quickcheck file
+    pub quickcheck: usize,
+    /// Files that were compressed into the cache
+    pub compress: usize,
+    /// Files that were stored into the cache without compression
+    pub stored: usize,
+    /// Files that changed since the previous manifest
+    pub updated: usize,
+    /// Files that were considered for the manifest
+    pub total: usize,
+    /// Files that were ignored due to the include / exclude globs
+    pub ignored: usize,
+    /// Files that could not be processed
+    pub failed: usize,
+    /// Files that were taken from the checkpoint of an interrupted run
+    pub resumed: usize,
 }
 
 struct Visitor {
@@ -78,35 +136,70 @@ struct Visitor {
     quickcheck: BTreeMap<u32, QuickCheck>,
     quickcheck_out: BufWriter<File>,
     conv: Converter,
-    output: PathBuf,
     /// The previous manifest
     prev: BTreeMap<String, FileLine>,
     /// The new manifest
     manifest: Manifest,
+    /// The path of the minimal manifest and the PKI it leaves out
+    packed: Option<(PathBuf, PackIndexFile)>,
+    /// The dir with a copy of the manifests of every version
+    history: PathBuf,
+    /// The first error writing the quickcheck file
+    quickcheck_err: Option<std::io::Error>,
+    /// Whether the sd0 settings changed since the last full run
+    recompress: bool,
+    /// The files that were recompressed with the new settings
+    recompressed: HashSet<[u8; 16]>,
+    /// The project prefix, if paths are lowercased
+    lowercase: Option<String>,
+    /// The visited paths by their lowercase name
+    seen: HashMap<String, String>,
+    /// Pairs of paths that only differ in case
+    collisions: BTreeSet<(String, String)>,
+    /// The maximum length of a path in the manifest
+    max_path: usize,
+    /// Paths that the client can't use
+    invalid: BTreeMap<String, Problem>,
+    progress: ProgressBar,
+    /// The files processed by an interrupted run
+    resume: BTreeMap<String, checkpoint::Entry>,
+    /// The journal of this run
+    checkpoint: Option<Checkpoint>,
+    /// The number of bytes that were hashed
+    hashed_bytes: u64,
+    /// The number of (raw) bytes that were compressed
+    compressed_bytes: u64,
 }
 
 impl Visitor {
-    fn compress(&mut self, input: &Path, outpath: &Path) -> Option<FileMetaPair> {
-        // Continue with conversion if it was just not found
-        let parent = outpath.parent().unwrap();
-        if let Err(e) = std::fs::create_dir_all(parent) {
-            log::error!("Failed to create dir {}:\n\t{}", parent.display(), e);
-            return None;
-        }
-        log::info!("Converting {} to {}", input.display(), outpath.display());
-        match self.conv.convert_file(input, &outpath) {
-            Err(e) => {
-                log::error!(
-                    "Error converting {} to {}:\n\t{}",
-                    input.display(),
-                    outpath.display(),
-                    e
-                );
-                return None;
+    fn update_progress(&self) {
+        self.progress.set_message(format!(
+            "{} hashed, {} compressed",
+            HumanBytes(self.hashed_bytes),
+            HumanBytes(self.compressed_bytes)
+        ));
+    }
+
+    /// Get the manifest path for a scanned path
+    fn manifest_key(&self, path: String) -> String {
+        match &self.lowercase {
+            Some(prefix) => lowercase_path(prefix, &path),
+            None => path,
+        }
+    }
+
+    /// Remember that `path` was visited, returns `false` if another path differs only in case
+    fn check_case(&mut self, path: &str) -> bool {
+        match self.seen.entry(path.to_lowercase()) {
+            hash_map::Entry::Occupied(e) if e.get() != path => {
+                let other = e.get().clone();
+                self.collisions.insert((other, path.to_owned()));
+                false
             }
-            Ok(line) => {
-                self.stats.compress += 1;
-                Some(line)
+            hash_map::Entry::Occupied(_) => true,
+            hash_map::Entry::Vacant(e) => {
+                e.insert(path.to_owned());
+                true
             }
         }
     }
@@ -116,6 +209,18 @@ impl Visitor {
             self.stats.ignored += 1;
             return;
         }
+        if !self.check_case(&path) {
+            log::error!("{} collides with a path that differs only in case", path);
+            self.stats.failed += 1;
+            return;
+        }
+        let path = self.manifest_key(path);
+        if let Err(problem) = validate::check_path(&path, self.max_path) {
+            log::error!("{} can't be published: {}", path, problem);
+            self.invalid.insert(path, problem);
+            self.stats.failed += 1;
+            return;
+        }
         self.stats.total += 1;
         let crc = calculate_crc(path.as_bytes());
         let mtime = meta
@@ -124,26 +229,45 @@ impl Visitor {
             .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
             .as_ref()
             .map(Duration::as_secs_f64);
+
+        if let Some(entry) = self.resume.remove(&path) {
+            if mtime == Some(entry.mtime) {
+                self.quickcheck.remove(&crc);
+                self.prev.remove(&path);
+                self.recompressed.insert(entry.line.0.raw.hash.0);
+                self.stats.resumed += 1;
+                self.record(path, mtime, entry.line, false);
+                return;
+            }
+        }
         let _size = meta.as_ref().map(Metadata::len);
         let quickcheck = self.quickcheck.remove(&crc);
 
         let in_meta = match quickcheck {
             // FIXME: size check
-            Some(qc) if (mtime.is_some() && qc.mtime == mtime) => {
+            Some(qc) if (mtime.is_some() && qc.mtime == mtime && qc.path == path) => {
                 self.stats.quickcheck += 1;
                 qc.meta
             }
             _ => match md5::md5sum(input) {
-                Ok(meta) => meta,
+                Ok(meta) => {
+                    self.hashed_bytes += u64::from(meta.size);
+                    self.update_progress();
+                    meta
+                }
                 Err(e) => {
                     log::error!("Failed to check {}:\n\t{}", input.display(), e);
+                    self.stats.failed += 1;
                     return;
                 }
             },
         };
 
         let old_meta_pair = self.prev.remove(&path);
-        let mut meta_pair = old_meta_pair.filter(|(p, _)| p.raw == in_meta);
+        let mut meta_pair = old_meta_pair
+            .as_ref()
+            .filter(|(p, _)| p.raw == in_meta)
+            .cloned();
 
         if let (Some(old), None) = (old_meta_pair.as_ref(), meta_pair.as_ref()) {
             self.stats.updated += 1;
@@ -155,41 +279,75 @@ impl Visitor {
             );
         }
 
-        let outpath = self.output.join(hash_to_path(&in_meta.hash));
+        // The sd0 file of every distinct content is rewritten once with the new settings
+        let force = self.recompress && self.recompressed.insert(in_meta.hash.0);
+        if self.recompress {
+            meta_pair = None;
+        }
 
         if meta_pair.is_none() {
-            let line = match md5::md5sum(&outpath) {
-                Ok(meta) => FileMetaPair {
-                    raw: in_meta,
-                    compressed: meta,
-                },
-                Err(e) => {
-                    if e.kind() != ErrorKind::NotFound {
-                        log::error!("Failed to access {}:\n\t{}", outpath.display(), e);
-                        return;
+            let sd0 = match force {
+                true => self.conv.convert(&path, input, in_meta),
+                false => self.conv.find_or_convert(&path, input, in_meta),
+            };
+            let line = match sd0 {
+                Ok((line, origin)) => {
+                    match origin {
+                        Origin::Cached => {}
+                        Origin::Compressed => self.stats.compress += 1,
+                        Origin::Stored => self.stats.stored += 1,
                     }
-                    let Some(meta_pair) = self.compress(input, &outpath) else {
-                        return
-                    };
-                    meta_pair
+                    if origin != Origin::Cached {
+                        self.compressed_bytes += u64::from(line.raw.size);
+                        self.update_progress();
+                    }
+                    line
+                }
+                Err(e) => {
+                    log::error!("Failed to convert {} to sd0:\n\t{}", input.display(), e);
+                    self.stats.failed += 1;
+                    return;
                 }
             };
             let linesum = md5::MD5Sum::compute(&format!("{path},{line}"));
             meta_pair = Some((line, linesum));
         }
-        if let Some((meta_pair, linesum)) = meta_pair {
-            let qc = QuickCheck {
-                path: path.clone(),
-                mtime,
-                meta: in_meta,
-            };
-            qc.write(&mut self.quickcheck_out).unwrap();
+        if let Some(line) = meta_pair {
+            self.record(path, mtime, line, true);
+        }
+    }
 
-            self.manifest.files.insert(path, (meta_pair, linesum));
+    /// Add a file to the quickcheck file, the manifest and (if `journal` is set) the checkpoint
+    fn record(&mut self, path: String, mtime: Option<f64>, line: FileLine, journal: bool) {
+        let qc = QuickCheck {
+            path: path.clone(),
+            mtime,
+            meta: line.0.raw,
+        };
+        if let Err(e) = qc.write(&mut self.quickcheck_out) {
+            self.quickcheck_err.get_or_insert(e);
         }
+        if let (true, Some(checkpoint), Some(mtime)) = (journal, &mut self.checkpoint, mtime) {
+            if let Err(e) = checkpoint.write(&path, mtime, &line) {
+                log::warn!(
+                    "Failed to write checkpoint, the run can't be resumed:\n\t{}",
+                    e
+                );
+                self.checkpoint = None;
+            }
+        }
+        self.manifest.files.insert(path, line);
     }
 
-    fn do_scan_file(&mut self, real_proj_dir: &Path, line: &str, strip_prefix: &str) {
+    /// Visit a single listed file, with `removed` a missing file is an expected deletion
+    fn do_scan_file(
+        &mut self,
+        real_proj_dir: &Path,
+        line: &str,
+        strip_prefix: &str,
+        removed: bool,
+    ) {
+        self.progress.inc(1);
         let path = line.replace('/', "\\");
         let in_proj_path = match path.strip_prefix(strip_prefix) {
             Some(o) => o.trim(),
@@ -207,13 +365,17 @@ impl Visitor {
             Ok(meta) => Some(meta),
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 // If the file is explicitly listed but was no found, remove it
-                log::warn!("File {:?} not found!", path);
+                match removed {
+                    true => log::debug!("File {:?} was removed", path),
+                    false => log::warn!("File {:?} not found!", path),
+                }
+                let path = self.manifest_key(path);
                 let crc = calculate_crc(path.as_bytes());
-                if let Some(_) = self.quickcheck.remove(&crc) {
+                if self.quickcheck.remove(&crc).is_some() {
                     log::debug!("Removed {:?} from quickcheck", path);
                 }
-                if let Some(_) = self.prev.remove(&path) {
-                    log::info!("Removed {:?} from manifest", path);
+                if self.prev.remove(&path).is_some() {
+                    log::debug!("Removed {:?} from manifest", path);
                 }
                 return; // don't visit this file
             }
@@ -231,6 +393,7 @@ impl Visitor {
         file_list_reader: R,
         paths: &Paths,
         relative: bool,
+        removed: bool,
     ) -> color_eyre::Result<()> {
         let files = BufReader::new(file_list_reader);
         let strip_prefix = match relative {
@@ -238,7 +401,7 @@ impl Visitor {
             false => &paths.strip_prefix,
         };
         for line in files.lines() {
-            self.do_scan_file(&paths.proj_dir, line?.as_str(), strip_prefix);
+            self.do_scan_file(&paths.proj_dir, line?.as_str(), strip_prefix, removed);
         }
         Ok(())
     }
@@ -250,22 +413,196 @@ impl Visitor {
         relative: bool,
     ) -> color_eyre::Result<()> {
         if file_list_path == Path::new("-") {
-            self.do_scan_files(std::io::stdin(), paths, relative)
+            self.do_scan_files(std::io::stdin(), paths, relative, false)
         } else {
-            let file_list_reader = File::open(&file_list_path).wrap_err_with(|| {
+            let file_list_reader = File::open(file_list_path).wrap_err_with(|| {
                 format!("Failed to open files list: {}", file_list_path.display())
             })?;
-            self.do_scan_files(file_list_reader, paths, relative)
+            self.do_scan_files(file_list_reader, paths, relative, false)
+        }
+    }
+}
+
+impl Visitor {
+    /// Start a partial update based on the current manifest and quickcheck file
+    fn begin_update(&mut self, quickcheck_path: &Path) -> color_eyre::Result<()> {
+        let (quickcheck, quickcheck_file) = open_quickcheck(quickcheck_path)
+            .wrap_err_with(|| format!("Failed to open {}", quickcheck_path.display()))?;
+        self.quickcheck = quickcheck;
+        self.quickcheck_out = BufWriter::new(quickcheck_file);
+        self.prev = std::mem::take(&mut self.manifest.files);
+        self.stats = Stats::default();
+        self.seen.clear();
+        self.collisions.clear();
+        self.invalid.clear();
+        Ok(())
+    }
+
+    /// Keep the entries of all files that were not visited
+    fn keep_untouched(&mut self) -> std::io::Result<()> {
+        // Write out untouched manifest files
+        for (key, value) in std::mem::take(&mut self.prev) {
+            self.manifest.files.insert(key, value);
+        }
+        // Write out untouched quickcheck files
+        for (_key, value) in std::mem::take(&mut self.quickcheck) {
+            value.write(&mut self.quickcheck_out)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the quickcheck file and write the manifest
+    fn write_out(&mut self, quickcheck_path: &Path, manifest: &Path) -> color_eyre::Result<()> {
+        if let Some(e) = self.quickcheck_err.take() {
+            return Err(e)
+                .wrap_err_with(|| format!("Failed to write {}", quickcheck_path.display()));
+        }
+        self.quickcheck_out
+            .flush()
+            .wrap_err_with(|| format!("Failed to write {}", quickcheck_path.display()))?;
+        std::fs::rename(quickcheck_tmp(quickcheck_path), quickcheck_path)
+            .wrap_err_with(|| format!("Failed to replace {}", quickcheck_path.display()))?;
+        self.check_invalid()?;
+        self.check_collisions()?;
+        manifest::write_manifest(&self.manifest, manifest).context("Failed to write manifest")?;
+        self.keep_version(manifest)?;
+        if let Some((path, pack_index)) = &self.packed {
+            let (packed, missing) = manifest::packed_manifest(&self.manifest, pack_index);
+            for archive in missing {
+                log::warn!(
+                    "{} is not in the manifest, run `pack` and `cache` again",
+                    archive
+                );
+            }
+            log::info!(
+                "{} of {} files are not packed",
+                packed.files.len(),
+                self.manifest.files.len()
+            );
+            manifest::write_manifest(&packed, path).context("Failed to write packed manifest")?;
+            self.keep_version(path)?;
         }
+        Ok(())
+    }
+
+    /// Copy a manifest that was written to the history dir
+    fn keep_version(&self, path: &Path) -> color_eyre::Result<()> {
+        let target = history_path(&self.history, path, self.manifest.version.version);
+        std::fs::create_dir_all(&self.history)
+            .wrap_err_with(|| format!("Failed to create dir {}", self.history.display()))?;
+        std::fs::copy(path, &target)
+            .wrap_err_with(|| format!("Failed to copy manifest to {}", target.display()))?;
+        Ok(())
+    }
+}
+
+/// The dir with a copy of the manifests of every version, i.e. `{cache}/{name}.versions`
+///
+/// It is next to the cache dir, so it isn't published with the patch files.
+pub(crate) fn history_dir(paths: &Paths, name: &str) -> PathBuf {
+    paths.cache_dir_parent.join(format!("{}.versions", name))
+}
+
+impl Visitor {
+    /// Fail if any visited path can't be used by the Windows client
+    fn check_invalid(&self) -> color_eyre::Result<()> {
+        if self.invalid.is_empty() {
+            return Ok(());
+        }
+        let mut report = String::new();
+        for (path, problem) in &self.invalid {
+            report.push_str(&format!("\n\t{}: {}", path, problem));
+        }
+        Err(eyre!(
+            "Found {} paths that can't be published:{}",
+            self.invalid.len(),
+            report
+        ))
+    }
+
+    /// Fail if any two paths of the manifest only differ in case
+    ///
+    /// The CRC of a path is case-insensitive, so the client can't tell them apart.
+    fn check_collisions(&mut self) -> color_eyre::Result<()> {
+        let mut names = HashMap::new();
+        for key in self.manifest.files.keys() {
+            if let Some(other) = names.insert(key.to_lowercase(), key) {
+                self.collisions.insert((other.clone(), key.clone()));
+            }
+        }
+        if self.collisions.is_empty() {
+            return Ok(());
+        }
+        let mut report = String::new();
+        for (a, b) in &self.collisions {
+            report.push_str(&format!("\n\t{} and {}", a, b));
+        }
+        Err(eyre!(
+            "Found {} pairs of paths that differ only in case, rename or remove one of each:{}",
+            self.collisions.len(),
+            report
+        ))
     }
 }
 
 impl FsVisitor for Visitor {
     fn visit_file(&mut self, info: FileInfo) {
+        self.progress.inc(1);
         self.visit(info.path(), info.real(), info.metadata().ok())
     }
 }
 
+/// The file that the new quickcheck entries are written to
+///
+/// It replaces the quickcheck file in [`Visitor::write_out`], so an interrupted
+/// run keeps the previous one.
+fn quickcheck_tmp(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Load the quickcheck file and create the file for the new entries
+fn open_quickcheck(path: &Path) -> std::io::Result<(BTreeMap<u32, QuickCheck>, File)> {
+    let quickcheck = match File::open(path) {
+        Ok(mut file) => scan_quickcheck(&mut file),
+        Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e),
+    };
+    let file = File::create(quickcheck_tmp(path))?;
+    Ok((quickcheck, file))
+}
+
+/// Count the files in the project for the progress bar
+///
+/// This is skipped if the progress bar is hidden anyway.
+fn count_files(paths: &Paths) -> Option<u64> {
+    struct Counter(u64);
+    impl FsVisitor for Counter {
+        fn visit_file(&mut self, _info: FileInfo) {
+            self.0 += 1;
+        }
+    }
+    if progress::multi().is_hidden() {
+        return None;
+    }
+    let mut counter = Counter(0);
+    scan_dir(&mut counter, paths.prefix.clone(), &paths.proj_dir, true);
+    Some(counter.0)
+}
+
+/// Load the sd0 settings of the last full run
+///
+/// A cache without a settings file was created with the defaults.
+fn read_settings(path: &Path) -> color_eyre::Result<Settings> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Settings::parse(&text)
+            .ok_or_else(|| eyre!("Invalid sd0 settings in {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Settings::default()),
+        Err(e) => Err(e).wrap_err_with(|| format!("Failed to read {}", path.display())),
+    }
+}
+
 fn include_glob(project: &ProjectConfig) -> Result<GlobSet, globset::Error> {
     let mut builder = GlobSetBuilder::new();
     if project.include.is_empty() {
@@ -278,6 +615,7 @@ fn include_glob(project: &ProjectConfig) -> Result<GlobSet, globset::Error> {
     builder.build()
 }
 
+/// Get the patterns of the compression policy, in order
 fn exclude_glob(project: &ProjectConfig) -> Result<GlobSet, globset::Error> {
     let mut builder = GlobSetBuilder::new();
     for pattern in &project.exclude {
@@ -286,8 +624,12 @@ fn exclude_glob(project: &ProjectConfig) -> Result<GlobSet, globset::Error> {
     builder.build()
 }
 
-pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
-    let paths = args.paths();
+/// Scan the project, update the sd0 cache and write the manifest
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<Stats> {
+    let paths = args.paths()?;
+    if args.cmd.files.is_some() && args.cmd.since.is_some() {
+        return Err(eyre!("`--files` and `--since` can't be used together"));
+    }
 
     let quickcheck_path = paths
         .cache_dir_parent
@@ -295,17 +637,82 @@ pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
     let output = paths.cache_dir.clone();
     std::fs::create_dir_all(&output).wrap_err("Failed to create output dir")?;
 
-    let include_glob = include_glob(&args.project)?;
-    let exclude_glob = exclude_glob(&args.project)?;
+    if let Err(problem) = validate::check_path(&paths.prefix, args.project.max_path) {
+        return Err(eyre!(
+            "Invalid project prefix {:?}: {}",
+            paths.prefix,
+            problem
+        ));
+    }
+
+    let include_glob = include_glob(args.project)?;
+    let exclude_glob = exclude_glob(args.project)?;
+
+    // Ask git before opening the quickcheck file
+    let since = match &args.cmd.since {
+        Some(rev) => {
+            let files = git::changed_files(&paths.proj_dir, rev)?;
+            log::info!("{} files changed since {}", files.len(), rev);
+            Some(files)
+        }
+        None => None,
+    };
+
+    let settings = Settings {
+        level: Settings::check_level(args.cmd.level.or(args.project.compression))?,
+        segment_index: args.cmd.segment_index || args.project.segment_index,
+        policy: match args.project.policy.is_empty() {
+            true => None,
+            false => Some(MD5Sum::compute(&format!(
+                "{:?},{}",
+                args.project.policy, args.project.auto_ratio
+            ))),
+        },
+    };
+    let settings_path = paths
+        .cache_dir_parent
+        .join(format!("{}.sd0.txt", args.name));
+    let prev_settings = read_settings(&settings_path)?;
+    // The sd0 files are shared by all files with the same content, so a partial run
+    // can't rewrite them without breaking the manifest lines it doesn't scan
+    let full = args.cmd.files.is_none() && since.is_none();
+    let settings = match (prev_settings != settings, full) {
+        (true, true) => {
+            log::info!(
+                "sd0 settings changed from {} to {}, recompressing",
+                prev_settings,
+                settings
+            );
+            settings
+        }
+        (true, false) => {
+            log::warn!(
+                "sd0 settings changed from {} to {}, keeping the old ones until a full `cache` run",
+                prev_settings,
+                settings
+            );
+            prev_settings
+        }
+        (false, _) => settings,
+    };
+    let recompress = prev_settings != settings;
+
+    let checkpoint_path = paths
+        .cache_dir_parent
+        .join(format!("{}.checkpoint.txt", args.name));
+    let resume = checkpoint::load(&checkpoint_path, &settings)
+        .wrap_err_with(|| format!("Failed to load {}", checkpoint_path.display()))?;
+    if !resume.is_empty() {
+        log::info!(
+            "Resuming an interrupted run with {} files done",
+            resume.len()
+        );
+    }
+    let checkpoint = Checkpoint::open(&checkpoint_path, &settings, !resume.is_empty())
+        .wrap_err_with(|| format!("Failed to open {}", checkpoint_path.display()))?;
 
-    let mut _quickcheck = File::options()
-        .create(true)
-        .write(true)
-        .read(true)
-        .open(&quickcheck_path)?;
-    let quickcheck = scan_quickcheck(&mut _quickcheck);
-    _quickcheck.seek(SeekFrom::Start(0))?;
-    _quickcheck.set_len(0)?; // clear the file
+    let (quickcheck, quickcheck_file) = open_quickcheck(&quickcheck_path)
+        .wrap_err_with(|| format!("Failed to open {}", quickcheck_path.display()))?;
 
     let proj_dir = &paths.proj_dir;
 
@@ -316,6 +723,17 @@ pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
     let vname = args.cmd.name.unwrap_or_else(|| vnum.to_string());
     let version = VersionLine::new(vnum, vname);
 
+    let packed = match &args.project.packed_manifest {
+        Some(name) => {
+            let pki_path = output.join(&args.project.pki).with_extension("pki");
+            let pack_index = PackIndexFile::from_file(&pki_path)
+                .wrap_err_with(|| format!("Failed to load {}", pki_path.display()))
+                .suggestion("`packed_manifest` needs the PKI, run `lupdate pki` first")?;
+            Some((output.join(name).with_extension("txt"), pack_index))
+        }
+        None => None,
+    };
+
     let prev = match std::fs::metadata(&manifest) {
         Ok(m) if m.is_file() => {
             let mf = Manifest::from_file(&manifest)?;
@@ -334,40 +752,83 @@ pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
         exclude_glob,
         stats: Stats::default(),
         quickcheck,
-        quickcheck_out: BufWriter::new(_quickcheck),
+        quickcheck_out: BufWriter::new(quickcheck_file),
         prev,
         manifest: Manifest {
             version,
             files: BTreeMap::new(),
         },
+        packed,
+        history: history_dir(&paths, args.name),
         conv: Converter {
-            generate_segment_index: false,
+            settings,
+            output,
+            policies: Policies::for_project(args.project)?,
+        },
+        quickcheck_err: None,
+        recompress,
+        recompressed: HashSet::new(),
+        lowercase: match args.project.lowercase {
+            true => Some(paths.strip_prefix.clone()),
+            false => None,
         },
-        output,
+        seen: HashMap::new(),
+        collisions: BTreeSet::new(),
+        max_path: args.project.max_path,
+        invalid: BTreeMap::new(),
+        progress: ProgressBar::hidden(),
+        resume,
+        checkpoint: Some(checkpoint),
+        hashed_bytes: 0,
+        compressed_bytes: 0,
     };
 
     log::info!("Scanning {} as {}", proj_dir.display(), paths.prefix);
 
     if let Some(file_list_path) = args.cmd.files {
+        visitor.progress = progress::bar(None, "files");
         visitor.scan_files(&file_list_path, &paths, args.cmd.relative)?;
-        // Write out untouched manifest files
-        for (key, value) in visitor.prev {
-            visitor.manifest.files.insert(key, value);
-        }
-        // Write out untouched quickcheck files
-        for (_key, value) in visitor.quickcheck {
-            value.write(&mut visitor.quickcheck_out)?;
-        }
+        visitor.keep_untouched()?;
+    } else if let Some(files) = since {
+        let list: String = files
+            .iter()
+            .map(|file| format!("{}{}\n", paths.strip_prefix, file))
+            .collect();
+        visitor.progress = progress::bar(Some(files.len() as u64), "files");
+        // git lists the deleted files too, so missing files are expected here
+        visitor.do_scan_files(list.as_bytes(), &paths, false, true)?;
+        visitor.keep_untouched()?;
     } else {
-        scan_dir(&mut visitor, paths.prefix, &proj_dir, true);
-        for (k, _v) in visitor.prev {
-            log::info!("File {} was removed", k);
+        visitor.progress = progress::bar(count_files(&paths), "files");
+        scan_dir(&mut visitor, paths.prefix.clone(), proj_dir, true);
+        let removed = std::mem::take(&mut visitor.prev);
+        for k in removed.keys() {
+            log::debug!("File {} was removed", k);
+        }
+        if !removed.is_empty() {
+            log::info!("{} files were removed", removed.len());
         }
     }
+    visitor.progress.finish_and_clear();
+    // Batches in watch mode are too small for a progress bar
+    visitor.progress = ProgressBar::hidden();
+    visitor.write_out(&quickcheck_path, &manifest)?;
+    visitor.checkpoint = None;
+    visitor.resume.clear();
+    std::fs::remove_file(&checkpoint_path)
+        .wrap_err_with(|| format!("Failed to remove {}", checkpoint_path.display()))?;
+
+    if recompress {
+        std::fs::write(&settings_path, format!("{}\n", settings))
+            .wrap_err_with(|| format!("Failed to write {}", settings_path.display()))?;
+    }
+    // Later batches use the previous sd0 files as they are
+    visitor.recompress = false;
 
-    manifest::write_manifest(visitor.manifest, &manifest).context("Failed to write manifest")?;
-
-    log::info!("{:?}", visitor.stats);
+    if args.cmd.watch {
+        log::info!("{:?}", visitor.stats);
+        watch::watch(&mut visitor, &paths, &quickcheck_path, &manifest)?;
+    }
 
-    Ok(())
+    Ok(visitor.stats)
 }
@@ -4,7 +4,7 @@ use std::{
     io::{self, BufRead, BufReader, BufWriter, Write},
 };
 
-use assembly_pack::{crc::calculate_crc, md5::MD5Sum, txt::FileMeta};
+use assembly_pack::{crc::calculate_crc, txt::FileMeta};
 
 pub(super) struct QuickCheck {
     pub path: String,
@@ -15,18 +15,31 @@ pub(super) struct QuickCheck {
 impl QuickCheck {
     pub fn write(&self, out: &mut BufWriter<File>) -> io::Result<()> {
         out.write_all(self.path.as_bytes())?;
-        out.write(b",")?;
+        out.write_all(b",")?;
         if let Some(mtime) = self.mtime {
             write!(out, "{}", mtime)?;
         }
-        out.write(b",")?;
+        out.write_all(b",")?;
         write!(out, "{}", self.meta.size)?;
-        out.write(b",")?;
+        out.write_all(b",")?;
         writeln!(out, "{}", self.meta.hash)?;
         Ok(())
     }
 }
 
+fn parse_line(line: &str) -> Option<QuickCheck> {
+    let mut fields = line.split(',');
+    let path = fields.next()?;
+    let mtime = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let hash = fields.next()?.trim().parse().ok()?;
+    Some(QuickCheck {
+        path: path.to_owned(),
+        mtime: Some(mtime),
+        meta: FileMeta { size, hash },
+    })
+}
+
 pub(super) fn scan_quickcheck<R: io::Read>(reader: &mut R) -> BTreeMap<u32, QuickCheck> {
     let mut quickcheck = BTreeMap::new();
     let mut reader = BufReader::new(reader);
@@ -35,21 +48,9 @@ pub(super) fn scan_quickcheck<R: io::Read>(reader: &mut R) -> BTreeMap<u32, Quic
         if len == 0 {
             break;
         }
-        let mut fields = buffer.split(',');
-        let path = fields.next().unwrap();
-        let crc = calculate_crc(path.as_bytes());
-        let mtime_str = fields.next().unwrap();
-        if let Ok(mtime) = mtime_str.parse() {
-            let size: u32 = fields.next().unwrap().parse().unwrap();
-            let hash: MD5Sum = fields.next().unwrap().trim().parse().unwrap();
-            quickcheck.insert(
-                crc,
-                QuickCheck {
-                    path: path.to_owned(),
-                    mtime: Some(mtime),
-                    meta: FileMeta { size, hash },
-                },
-            );
+        // Lines without an mtime or that are malformed just don't get a quickcheck
+        if let Some(qc) = parse_line(&buffer) {
+            quickcheck.insert(calculate_crc(qc.path.as_bytes()), qc);
         }
         buffer.clear();
     }
@@ -0,0 +1,375 @@
+//! Conversion to sd0 with a configurable compression level
+//!
+//! This follows [`assembly_pack::sd0::fs::Converter`], which always uses the best compression.
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use assembly_pack::{
+    common::{FileMeta, FileMetaPair},
+    md5::{io::IOSum, md5sum, MD5Sum},
+    sd0::{
+        index::{HeaderLine, SegmentLine},
+        Compression, MAGIC,
+    },
+};
+use color_eyre::eyre::eyre;
+use flate2::{Compress, FlushCompress};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::config::{Policy, ProjectConfig};
+
+use super::hash_to_path;
+
+const SEGMENT_SIZE: u32 = 0x40000;
+const CHUNK_LEN: usize = SEGMENT_SIZE as usize;
+
+const fn compress_bound(source_len: usize) -> usize {
+    source_len + (source_len >> 12) + (source_len >> 14) + (source_len >> 25) + 13
+}
+
+const CHUNK_BOUND: usize = compress_bound(CHUNK_LEN);
+
+/// A unique path next to `path` to write to before renaming it into place
+///
+/// The cache is content-addressed, so an existing sd0 file is trusted to be complete,
+/// even after an interrupted run or when two threads convert the same content.
+fn tmp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}-{}.tmp", std::process::id(), n));
+    path.with_file_name(name)
+}
+
+/// Write a file with `write` to a temporary path, then rename it to `path`
+fn write_atomic<T>(path: &Path, write: impl FnOnce(&Path) -> io::Result<T>) -> io::Result<T> {
+    let tmp = tmp_path(path);
+    let result = write(&tmp).and_then(|value| {
+        std::fs::rename(&tmp, path)?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// The settings that affect the contents of the sd0 files
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// The zlib compression level (0-9)
+    pub level: u32,
+    /// Whether to generate `si0` segment index files
+    pub segment_index: bool,
+    /// A hash of the per-file compression policy, if there is one
+    pub policy: Option<MD5Sum>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            level: Compression::best().level(),
+            segment_index: false,
+            policy: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Check a configured zlib compression level, defaulting to the best compression
+    pub fn check_level(level: Option<u32>) -> color_eyre::Result<u32> {
+        match level {
+            Some(level) if level > 9 => {
+                Err(eyre!("Invalid compression level {}, expected 0-9", level))
+            }
+            Some(level) => Ok(level),
+            None => Ok(Self::default().level),
+        }
+    }
+
+    /// The settings from the project config, for files that are converted outside of `cache`
+    ///
+    /// The policy is left out, it only decides how `cache` treats each file.
+    pub fn for_project(project: &ProjectConfig) -> color_eyre::Result<Self> {
+        Ok(Self {
+            level: Self::check_level(project.compression)?,
+            segment_index: project.segment_index,
+            policy: None,
+        })
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "level={},segment_index={}",
+            self.level, self.segment_index
+        )?;
+        if let Some(policy) = &self.policy {
+            write!(f, ",policy={:?}", policy)?;
+        }
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Parse the output of the [`fmt::Display`] implementation
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut settings = Self::default();
+        for pair in text.trim().split(',') {
+            match pair.split_once('=')? {
+                ("level", level) => settings.level = level.parse().ok()?,
+                ("segment_index", flag) => settings.segment_index = flag.parse().ok()?,
+                ("policy", hash) => settings.policy = Some(hash.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(settings)
+    }
+}
+
+/// The per-file compression policy of a project
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Policies {
+    /// The `policy` patterns of the project
+    glob: GlobSet,
+    /// The policy for each pattern in `glob`
+    policies: Vec<Policy>,
+    /// The compression ratio above which `auto` stores a file
+    auto_ratio: f64,
+}
+
+impl Policies {
+    pub fn for_project(project: &ProjectConfig) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in project.policy.keys() {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(Self {
+            glob: builder.build()?,
+            policies: project.policy.values().copied().collect(),
+            auto_ratio: project.auto_ratio,
+        })
+    }
+
+    /// Get the policy of the first pattern that matches `path`
+    fn get(&self, path: &str) -> Policy {
+        let first = self.glob.matches(path).into_iter().min();
+        first.map_or(Policy::Compress, |index| self.policies[index])
+    }
+}
+
+/// Where the sd0 file of [`Converter::find_or_convert`] came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Origin {
+    /// It was already in the cache
+    Cached,
+    /// It was compressed from the source file
+    Compressed,
+    /// It was created from the source file without compression
+    Stored,
+}
+
+/// SD0 Converter
+pub(crate) struct Converter {
+    pub settings: Settings,
+    /// The cache dir with the sd0 files
+    pub output: PathBuf,
+    pub policies: Policies,
+}
+
+impl Converter {
+    /// A converter with the settings and policy of the project, for `output`
+    pub fn for_project(project: &ProjectConfig, output: PathBuf) -> color_eyre::Result<Self> {
+        Ok(Self {
+            settings: Settings::for_project(project)?,
+            output,
+            policies: Policies::for_project(project)?,
+        })
+    }
+
+    /// Get the sd0 file for the content `raw` of `source`, converting it if it's not in the cache
+    ///
+    /// `name` is the manifest path that selects the compression policy.
+    pub fn find_or_convert(
+        &self,
+        name: &str,
+        source: &Path,
+        raw: FileMeta,
+    ) -> io::Result<(FileMetaPair, Origin)> {
+        let outpath = self.output.join(hash_to_path(&raw.hash));
+        match md5sum(&outpath) {
+            Ok(compressed) => Ok((FileMetaPair { raw, compressed }, Origin::Cached)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.convert(name, source, raw),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Convert `source` to its sd0 file in the cache, replacing an existing one
+    pub fn convert(
+        &self,
+        name: &str,
+        source: &Path,
+        raw: FileMeta,
+    ) -> io::Result<(FileMetaPair, Origin)> {
+        let outpath = self.output.join(hash_to_path(&raw.hash));
+        if let Some(parent) = outpath.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let store = match self.policies.get(name) {
+            Policy::Compress => false,
+            Policy::Store => true,
+            Policy::Auto => self.sample_ratio(source)? > self.policies.auto_ratio,
+        };
+        let origin = match store {
+            true => {
+                log::debug!("Storing {} as {}", source.display(), outpath.display());
+                Origin::Stored
+            }
+            false => {
+                log::debug!("Converting {} to {}", source.display(), outpath.display());
+                Origin::Compressed
+            }
+        };
+        Ok((self.convert_file(source, &outpath, store)?, origin))
+    }
+
+    /// Compress the first segment of a file and return the compressed size relative to the raw size
+    pub fn sample_ratio(&self, input: &Path) -> io::Result<f64> {
+        let mut raw = Vec::<u8>::with_capacity(CHUNK_LEN);
+        File::open(input)?
+            .take(CHUNK_LEN as u64)
+            .read_to_end(&mut raw)?;
+        if raw.is_empty() {
+            return Ok(0.0);
+        }
+        let mut compressed = Vec::<u8>::with_capacity(CHUNK_BOUND);
+        let mut cmp = Compress::new(Compression::new(self.settings.level), true);
+        cmp.compress_vec(&raw, &mut compressed, FlushCompress::Finish)?;
+        Ok(compressed.len() as f64 / raw.len() as f64)
+    }
+
+    /// Convert a file to sd0
+    ///
+    /// If `store` is set, the segments are written without compression.
+    /// `output` only appears once it was written completely.
+    pub fn convert_file(
+        &self,
+        input: &Path,
+        output: &Path,
+        store: bool,
+    ) -> io::Result<FileMetaPair> {
+        write_atomic(output, |tmp| self.write_sd0(input, tmp, output, store))
+    }
+
+    /// Convert a file to sd0 at `tmp`, and write the index for `output` if enabled
+    fn write_sd0(
+        &self,
+        input: &Path,
+        tmp: &Path,
+        output: &Path,
+        store: bool,
+    ) -> io::Result<FileMetaPair> {
+        let mut input_file = IOSum::new(File::open(input)?);
+        let mut output_file = IOSum::new(BufWriter::new(File::create(tmp)?));
+
+        let mut index_file = match self.settings.segment_index {
+            true => Some(Vec::<u8>::new()),
+            false => None,
+        };
+
+        let mut raw = Vec::<u8>::with_capacity(CHUNK_LEN);
+        let mut compressed = Vec::<u8>::with_capacity(CHUNK_BOUND);
+
+        let mut start: u32 = 0;
+        let mut compressed_start: u32 = 0;
+
+        let level = match store {
+            true => Compression::none(),
+            false => Compression::new(self.settings.level),
+        };
+        let mut cmp = Compress::new(level, true);
+
+        output_file.write_all(MAGIC)?;
+
+        loop {
+            let mut limited = input_file.take(CHUNK_LEN as u64);
+            let size = limited.read_to_end(&mut raw)? as u32;
+            input_file = limited.into_inner();
+
+            if size == 0 {
+                break;
+            }
+
+            cmp.compress_vec(&raw, &mut compressed, FlushCompress::Finish)?;
+            cmp.reset();
+
+            let compressed_size = compressed.len() as u32;
+
+            if let Some(index_data) = &mut index_file {
+                let line = SegmentLine {
+                    start,
+                    size,
+                    adler: adler32::adler32(Cursor::new(&raw))?,
+                    raw_hash: MD5Sum::compute(&raw),
+                    compressed_start,
+                    compressed_size,
+                    compressed_hash: MD5Sum::compute(&compressed),
+                };
+                write!(index_data, "{}\r", line)?;
+            }
+
+            compressed_start += 4;
+            output_file.write_all(&compressed_size.to_le_bytes())?;
+            compressed_start += compressed_size;
+            output_file.write_all(&compressed)?;
+
+            start += size;
+
+            // Clear the buffers
+            raw.clear();
+            compressed.clear();
+
+            if (size as usize) < CHUNK_LEN {
+                break;
+            }
+        }
+
+        output_file.flush()?;
+
+        let raw_meta = FileMeta {
+            size: input_file.byte_count() as u32,
+            hash: input_file.digest(),
+        };
+        let compressed_meta = FileMeta {
+            size: output_file.byte_count() as u32,
+            hash: output_file.digest(),
+        };
+
+        if let Some(index_data) = index_file {
+            let header = HeaderLine {
+                magic: "si0\\x01\\xff",
+                raw_size: raw_meta.size,
+                raw_hash: raw_meta.hash,
+                segment_size: SEGMENT_SIZE,
+            };
+            write_atomic(&output.with_extension("si0"), |path| {
+                let mut writer = BufWriter::new(File::create(path)?);
+                write!(writer, "{}\r", header)?;
+                writer.write_all(&index_data)?;
+                writer.flush()
+            })?;
+        }
+
+        Ok(FileMetaPair {
+            raw: raw_meta,
+            compressed: compressed_meta,
+        })
+    }
+}
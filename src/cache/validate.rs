@@ -0,0 +1,133 @@
+//! Checks that manifest paths can be used by the Windows client
+use std::fmt;
+
+/// Characters that are not allowed in a Windows file name
+const ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '/', '|', '?', '*'];
+
+/// Names that refer to devices on Windows, even with an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why a path can't be published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Problem {
+    /// The client only handles ASCII paths
+    NonAscii(char),
+    /// The character is not allowed on Windows
+    IllegalChar(char),
+    /// Commas separate the fields of a manifest line
+    Comma,
+    /// The name is a device name on Windows
+    Reserved(String),
+    /// Windows strips a trailing dot or space from a name
+    Trailing(String),
+    /// The path is longer than the configured limit
+    TooLong(usize, usize),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonAscii(c) => write!(f, "non-ASCII character {:?}", c),
+            Self::IllegalChar(c) => write!(f, "character {:?} is not allowed on Windows", c),
+            Self::Comma => write!(f, "commas break the manifest format"),
+            Self::Reserved(name) => write!(f, "{} is a reserved name on Windows", name),
+            Self::Trailing(name) => write!(f, "{:?} ends with a dot or space", name),
+            Self::TooLong(len, max) => {
+                write!(f, "path is {} characters long, the limit is {}", len, max)
+            }
+        }
+    }
+}
+
+/// Check a backslash separated path, returning the first problem
+pub(super) fn check_path(path: &str, max_len: usize) -> Result<(), Problem> {
+    for c in path.chars() {
+        if !c.is_ascii() {
+            return Err(Problem::NonAscii(c));
+        }
+        if c == ',' {
+            return Err(Problem::Comma);
+        }
+        if c.is_ascii_control() || ILLEGAL_CHARS.contains(&c) {
+            return Err(Problem::IllegalChar(c));
+        }
+    }
+    for name in path.split('\\') {
+        let stem = name.split('.').next().unwrap_or(name);
+        if RESERVED_NAMES
+            .iter()
+            .any(|reserved| stem.trim_end().eq_ignore_ascii_case(reserved))
+        {
+            return Err(Problem::Reserved(name.to_owned()));
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            return Err(Problem::Trailing(name.to_owned()));
+        }
+    }
+    if path.len() > max_len {
+        return Err(Problem::TooLong(path.len(), max_len));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_path, Problem};
+
+    const MAX: usize = 260;
+
+    #[test]
+    fn valid_paths() {
+        assert_eq!(check_path("client\\res\\textures\\grass.dds", MAX), Ok(()));
+        assert_eq!(check_path("client\\res\\CONSOLE.dds", MAX), Ok(()));
+        assert_eq!(check_path("client\\res\\con_x.dds", MAX), Ok(()));
+        assert_eq!(check_path("client\\res\\.hidden", MAX), Ok(()));
+    }
+
+    #[test]
+    fn non_ascii() {
+        let problem = check_path("client\\res\\café.dds", MAX);
+        assert_eq!(problem, Err(Problem::NonAscii('é')));
+    }
+
+    #[test]
+    fn illegal_char() {
+        let problem = check_path("client\\res\\what?.dds", MAX);
+        assert_eq!(problem, Err(Problem::IllegalChar('?')));
+        let problem = check_path("client\\res\\tab\t.dds", MAX);
+        assert_eq!(problem, Err(Problem::IllegalChar('\t')));
+    }
+
+    #[test]
+    fn comma() {
+        let problem = check_path("client\\res\\a,b.dds", MAX);
+        assert_eq!(problem, Err(Problem::Comma));
+    }
+
+    #[test]
+    fn reserved() {
+        let problem = check_path("client\\res\\CON.dds", MAX);
+        assert_eq!(problem, Err(Problem::Reserved("CON.dds".to_owned())));
+        let problem = check_path("client\\lpt1\\a.dds", MAX);
+        assert_eq!(problem, Err(Problem::Reserved("lpt1".to_owned())));
+    }
+
+    #[test]
+    fn trailing() {
+        let problem = check_path("client\\res\\name.", MAX);
+        assert_eq!(problem, Err(Problem::Trailing("name.".to_owned())));
+        let problem = check_path("client\\res \\a.dds", MAX);
+        assert_eq!(problem, Err(Problem::Trailing("res ".to_owned())));
+    }
+
+    #[test]
+    fn too_long() {
+        let path = format!("client\\{}.dds", "a".repeat(20));
+        assert_eq!(check_path(&path, path.len()), Ok(()));
+        let problem = check_path(&path, 20);
+        assert_eq!(problem, Err(Problem::TooLong(path.len(), 20)));
+    }
+}
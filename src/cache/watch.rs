@@ -0,0 +1,95 @@
+use std::{collections::BTreeSet, path::Path, sync::mpsc, time::Duration};
+
+use assembly_pack::common::fs::scan_dir;
+use color_eyre::eyre::Context;
+use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode};
+
+use crate::Paths;
+
+use super::Visitor;
+
+/// How long the tree needs to be quiet before a batch of changes is processed
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Get the manifest path for a path in the project dir
+fn manifest_path(paths: &Paths, real: &Path) -> Option<String> {
+    let relative = real.strip_prefix(&paths.proj_dir).ok()?;
+    let mut path = paths.strip_prefix.clone();
+    for (i, part) in relative.iter().enumerate() {
+        if i > 0 {
+            path.push('\\');
+        }
+        path.push_str(part.to_str()?);
+    }
+    Some(path)
+}
+
+/// Process one settled batch of changed paths
+fn update(visitor: &mut Visitor, paths: &Paths, changed: BTreeSet<std::path::PathBuf>) {
+    for real in changed {
+        let Some(path) = manifest_path(paths, &real) else {
+            log::warn!("Ignoring change to {}", real.display());
+            continue;
+        };
+        if real.is_dir() {
+            // e.g. a directory that was moved into the tree
+            scan_dir(visitor, path, &real, true);
+        } else if real.exists()
+            || visitor
+                .prev
+                .contains_key(&visitor.manifest_key(path.clone()))
+        {
+            visitor.do_scan_file(&paths.proj_dir, &path, &paths.strip_prefix, true);
+        } else {
+            // A directory that was removed, drop everything below it
+            let dir = format!("{}\\", visitor.manifest_key(path));
+            let removed: Vec<String> = visitor
+                .prev
+                .range(dir.clone()..)
+                .take_while(|(k, _)| k.starts_with(&dir))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for file in removed {
+                visitor.do_scan_file(&paths.proj_dir, &file, &paths.strip_prefix, true);
+            }
+        }
+    }
+}
+
+/// Watch the project dir and update the cache for every settled batch of changes
+pub(super) fn watch(
+    visitor: &mut Visitor,
+    paths: &Paths,
+    quickcheck_path: &Path,
+    manifest: &Path,
+) -> color_eyre::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut debouncer = new_debouncer(SETTLE_TIME, tx).context("Failed to create watcher")?;
+    debouncer
+        .watcher()
+        .watch(&paths.proj_dir, RecursiveMode::Recursive)
+        .wrap_err_with(|| format!("Failed to watch {}", paths.proj_dir.display()))?;
+
+    log::info!("Watching {} for changes", paths.proj_dir.display());
+    for result in rx {
+        let events = match result {
+            Ok(events) => events,
+            Err(e) => {
+                log::error!("Failed to watch files: {}", e);
+                continue;
+            }
+        };
+        let changed: BTreeSet<_> = events.into_iter().map(|e| e.path).collect();
+        log::info!("{} paths changed", changed.len());
+
+        visitor.begin_update(quickcheck_path)?;
+        update(visitor, paths, changed);
+        visitor.keep_untouched()?;
+        match visitor.write_out(quickcheck_path, manifest) {
+            Ok(()) => log::info!("{:?}", visitor.stats),
+            // e.g. a case collision, which the next change may fix
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+    Ok(())
+}
@@ -4,6 +4,7 @@ use std::{
 };
 
 use color_eyre::eyre::Context;
+use indexmap::IndexMap;
 use serde::Deserialize;
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -97,6 +98,13 @@ pub struct ProjectConfig {
     #[serde(default = "default_manifest")]
     pub manifest: PathBuf,
 
+    /// Name of a minimal manifest for a client that uses the PK archives
+    ///
+    /// relative to `{cache}/{key}`. `cache` writes it next to the full manifest,
+    /// without the files in the PKI, but with the PK archives themselves.
+    #[serde(default)]
+    pub packed_manifest: Option<PathBuf>,
+
     /// The path of the `res` dir that is considered for packing
     ///
     /// relative to each project source dir
@@ -108,6 +116,137 @@ pub struct ProjectConfig {
     /// relative to the parent of the project directory e.g. `<project-name>/res`
     #[serde(default)]
     pub prefix: Option<String>,
+
+    /// The zlib compression level (0-9) of the sd0 files
+    ///
+    /// defaults to `9` (best)
+    #[serde(default)]
+    pub compression: Option<u32>,
+
+    /// Whether to generate `si0` segment index files next to the sd0 files
+    #[serde(default)]
+    pub segment_index: bool,
+
+    /// Lowercase all paths (below the prefix) in the manifest and PKI
+    ///
+    /// The client runs on case-insensitive filesystems, this keeps
+    /// the names consistent no matter how the files are named on disk.
+    #[serde(default)]
+    pub lowercase: bool,
+
+    /// The maximum length of a path in the manifest
+    ///
+    /// defaults to `200`, which leaves room for the install dir
+    /// within the 260 characters of `MAX_PATH` on Windows
+    #[serde(default = "max_path")]
+    pub max_path: usize,
+
+    /// Glob patterns (on the manifest path) mapped to how those files are cached
+    ///
+    /// The first matching pattern wins, all other files are compressed
+    #[serde(default)]
+    pub policy: IndexMap<String, Policy>,
+
+    /// Files in `auto` mode are stored if their first segment compresses
+    /// to more than this fraction of its size
+    ///
+    /// defaults to `0.9`
+    #[serde(default = "auto_ratio")]
+    pub auto_ratio: f64,
+
+    /// Settings for the generated patcher files (`boot.cfg`, version index)
+    #[serde(default)]
+    pub patcher: Option<PatcherConfig>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How a file is stored in the sd0 cache
+pub enum Policy {
+    /// Compress with the configured level
+    Compress,
+    /// Wrap the raw data without compression, for already compressed formats
+    Store,
+    /// Store the file if it compresses poorly
+    Auto,
+}
+
+fn max_path() -> usize {
+    200
+}
+
+fn auto_ratio() -> f64 {
+    0.9
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// Settings for the files that point a client at the patch server
+pub struct PatcherConfig {
+    /// Name of the server shown in the patcher (`SERVERNAME`)
+    pub server_name: String,
+
+    /// Hostname or IP of the patch server (`PATCHSERVERIP`)
+    pub server: String,
+
+    /// Port of the patch server (`PATCHSERVERPORT`)
+    ///
+    /// defaults to `80`
+    #[serde(default = "patch_server_port")]
+    pub port: u16,
+
+    /// Directory on the patch server (`PATCHSERVERDIR`)
+    ///
+    /// defaults to `project.<name>.key`
+    pub dir: Option<String>,
+
+    /// Hostname or IP of the auth server (`AUTHSERVERIP`)
+    ///
+    /// defaults to `server`
+    pub auth_server: Option<String>,
+
+    /// Locale of the client (`LOCALE`)
+    ///
+    /// defaults to `en_US`
+    #[serde(default = "locale")]
+    pub locale: String,
+
+    /// Names of the manifests listed in the version index
+    ///
+    /// relative to `{cache}/{key}`, defaults to `[project.<name>.manifest]`
+    #[serde(default)]
+    pub manifests: Vec<PathBuf>,
+
+    /// Name of the generated boot config file
+    ///
+    /// relative to `{cache}/{key}`
+    #[serde(default = "boot_cfg")]
+    pub boot: PathBuf,
+
+    /// Name of the generated version index
+    ///
+    /// relative to `{cache}/{key}`
+    #[serde(default = "version_index")]
+    pub index: PathBuf,
+
+    /// Additional `boot.cfg` entries, e.g. `CRASHLOGURL = "0:http://..."`
+    #[serde(default)]
+    pub extra: IndexMap<String, String>,
+}
+
+fn patch_server_port() -> u16 {
+    80
+}
+
+fn locale() -> String {
+    String::from("en_US")
+}
+
+fn boot_cfg() -> PathBuf {
+    PathBuf::from("boot.cfg")
+}
+
+fn version_index() -> PathBuf {
+    PathBuf::from("index")
 }
 
 fn pk_index() -> PathBuf {
@@ -118,6 +257,17 @@ fn default_manifest() -> PathBuf {
     PathBuf::from("trunk")
 }
 
+/// The default name of the config file
+pub const CONFIG_FILE: &str = "LUpdate.toml";
+
+/// Find the config file in `start` or the closest of its parents
+pub fn find_config_file(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> color_eyre::Result<Self> {
         let path = path.as_ref();
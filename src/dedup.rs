@@ -0,0 +1,125 @@
+//! This tool lists the files of a manifest that have
+//! the same content, and how much space that costs
+//! in the PK archives.
+use std::{collections::BTreeMap, path::PathBuf};
+
+use argh::FromArgs;
+use assembly_pack::{common::FileMetaPair, crc::calculate_crc};
+
+use crate::{pki::is_compressed, ProjectArgs};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// report files with identical content
+#[argh(subcommand, name = "dedup")]
+pub struct Args {
+    /// path to the manifest (default: the project manifest)
+    #[argh(option, short = 'm')]
+    pub manifest: Option<PathBuf>,
+
+    /// path to the PKI file (default: the project PKI in the cache dir, if it exists)
+    #[argh(option)]
+    pub pki: Option<PathBuf>,
+
+    /// only report files of at least this many bytes
+    #[argh(option, default = "0")]
+    pub min_size: u32,
+}
+
+/// A file that shares its content with other files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    /// The path in the manifest
+    pub path: String,
+    /// The archive that the file is packed into
+    pub archive: Option<String>,
+    /// The number of bytes the file takes up in that archive
+    pub packed_size: u32,
+}
+
+/// Files with the same raw hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    /// The metadata shared by all files
+    pub meta: FileMetaPair,
+    /// The files with this content
+    pub files: Vec<Duplicate>,
+}
+
+impl Group {
+    /// The bytes that the shared sd0 file saves in the cache (and for downloads)
+    pub fn saved(&self) -> u64 {
+        (self.files.len() as u64 - 1) * u64::from(self.meta.compressed.size)
+    }
+
+    /// The bytes taken up by all but the first packed copy
+    pub fn wasted(&self) -> u64 {
+        let mut packed = self.files.iter().filter(|f| f.archive.is_some());
+        packed.next();
+        packed.map(|f| u64::from(f.packed_size)).sum()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+/// The result of a [`run`]
+pub struct Stats {
+    /// Groups of files with identical content
+    pub groups: Vec<Group>,
+    /// The bytes saved by the shared sd0 files
+    pub saved: u64,
+    /// The bytes taken up by duplicates in the PK archives
+    pub wasted: u64,
+}
+
+/// Group the files of the manifest by content
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<Stats> {
+    let paths = args.paths()?;
+    let (manifest, pack_index) = args.load_manifest_and_pki(
+        &paths,
+        args.cmd.manifest.as_deref(),
+        args.cmd.pki.as_deref(),
+    )?;
+
+    let mut by_hash: BTreeMap<[u8; 16], Group> = BTreeMap::new();
+    for (path, (meta, _)) in manifest.files {
+        if meta.raw.size < args.cmd.min_size {
+            continue;
+        }
+        let lookup = pack_index.as_ref().and_then(|pki| {
+            let lookup = pki.files.get(&calculate_crc(path.as_bytes()))?;
+            let archive = pki.archives.get(lookup.pack_file as usize)?;
+            Some((archive.path.clone(), is_compressed(lookup)))
+        });
+        let (archive, packed_size) = match lookup {
+            Some((archive, true)) => (Some(archive), meta.compressed.size),
+            Some((archive, false)) => (Some(archive), meta.raw.size),
+            None => (None, 0),
+        };
+        by_hash
+            .entry(meta.raw.hash.0)
+            .or_insert_with(|| Group {
+                meta,
+                files: Vec::new(),
+            })
+            .files
+            .push(Duplicate {
+                path,
+                archive,
+                packed_size,
+            });
+    }
+
+    let mut groups: Vec<Group> = by_hash
+        .into_values()
+        .filter(|g| g.files.len() > 1)
+        .collect();
+    groups.sort_by_key(|g| std::cmp::Reverse((g.wasted(), g.saved())));
+
+    let mut stats = Stats::default();
+    for group in &groups {
+        stats.saved += group.saved();
+        stats.wasted += group.wasted();
+    }
+    stats.groups = groups;
+
+    Ok(stats)
+}
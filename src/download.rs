@@ -0,0 +1,196 @@
+//! This tool estimates how many bytes a client needs to download
+//! to get from an older version to the latest one, by comparing
+//! the manifests that `cache` keeps for every version.
+//!
+//! The patcher downloads every file of the latest manifest whose
+//! hash differs from the local one, as an sd0 file. So a PK archive
+//! is downloaded whole, even if only one of its files changed.
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use argh::FromArgs;
+use assembly_pack::txt::Manifest;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Help,
+};
+
+use crate::{
+    cache::{history_dir, history_path},
+    load_manifest, ProjectArgs,
+};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// estimate the download size of an update to the latest version
+#[argh(subcommand, name = "download")]
+pub struct Args {
+    /// the version of the client (default: every version in the history)
+    #[argh(option)]
+    pub from: Option<u32>,
+
+    /// name of the manifest to compare (default: `packed_manifest` if set, else `manifest`)
+    #[argh(option, short = 'm')]
+    pub manifest: Option<PathBuf>,
+
+    /// number of largest downloads to list for `--from`
+    #[argh(option, default = "10")]
+    pub top: usize,
+}
+
+/// A file that a client has to download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    /// The path in the manifest
+    pub path: String,
+    /// The size of the sd0 file
+    pub size: u32,
+    /// Whether the file is a PK archive of the PKI
+    pub archive: bool,
+    /// Whether the client doesn't have the file at all
+    pub new: bool,
+}
+
+/// The files a client on one version needs to reach the latest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    /// The version of the client
+    pub from: u32,
+    /// The latest version
+    pub to: u32,
+    /// The files to download, largest first
+    pub files: Vec<Download>,
+    /// The bytes to download, counting sd0 files with the same hash once
+    pub bytes: u64,
+    /// The number of files that are no longer in the manifest
+    pub removed: usize,
+}
+
+impl Update {
+    /// The bytes of the PK archives to download
+    pub fn archive_bytes(&self) -> u64 {
+        let archives = self.files.iter().filter(|f| f.archive);
+        archives.map(|f| u64::from(f.size)).sum()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+/// The result of a [`run`]
+pub struct Stats {
+    /// The update from each selected version, oldest first
+    pub updates: Vec<Update>,
+}
+
+/// Compare the manifest of a client with the latest one
+pub fn compare(old: &Manifest, new: &Manifest, archives: &HashSet<String>) -> Update {
+    let mut files = Vec::new();
+    let mut hashes = HashSet::new();
+    let mut bytes = 0;
+    for (path, (meta, _)) in &new.files {
+        let old_meta = old.files.get(path).map(|(meta, _)| meta);
+        if old_meta.is_some_and(|old| old.raw == meta.raw) {
+            continue;
+        }
+        // The patcher fetches sd0 files by hash, so identical content is fetched once
+        if hashes.insert(meta.compressed.hash.0) {
+            bytes += u64::from(meta.compressed.size);
+        }
+        files.push(Download {
+            path: path.clone(),
+            size: meta.compressed.size,
+            archive: archives.contains(path),
+            new: old_meta.is_none(),
+        });
+    }
+    files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    let removed = old
+        .files
+        .keys()
+        .filter(|path| !new.files.contains_key(*path))
+        .count();
+    Update {
+        from: old.version.version,
+        to: new.version.version,
+        files,
+        bytes,
+        removed,
+    }
+}
+
+/// The versions of the manifest `name` in the history dir
+fn history(dir: &Path, name: &Path) -> color_eyre::Result<BTreeMap<u32, PathBuf>> {
+    let mut versions = BTreeMap::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(versions),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Failed to read {}", dir.display())),
+    };
+    let stem = name
+        .file_stem()
+        .unwrap_or(name.as_os_str())
+        .to_string_lossy();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        let version = file_name
+            .strip_prefix(&*stem)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(".txt"))
+            .and_then(|version| version.parse().ok());
+        if let Some(version) = version {
+            versions.insert(version, history_path(dir, name, version));
+        }
+    }
+    Ok(versions)
+}
+
+/// Estimate the downloads to the latest version
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<Stats> {
+    let paths = args.paths()?;
+    let output = &paths.cache_dir;
+
+    let name = match &args.cmd.manifest {
+        Some(name) => name.clone(),
+        None => (args.project.packed_manifest.as_ref())
+            .unwrap_or(&args.project.manifest)
+            .clone(),
+    };
+    let latest_path = output.join(&name).with_extension("txt");
+    let (latest, pack_index) = args.load_manifest_and_pki(&paths, Some(&latest_path), None)?;
+    let archives: HashSet<String> = match pack_index {
+        Some(pack_index) => pack_index.archives.into_iter().map(|a| a.path).collect(),
+        None => HashSet::new(),
+    };
+
+    let history_dir = history_dir(&paths, args.name);
+    let mut versions = history(&history_dir, &name)?;
+    versions.retain(|version, _| *version < latest.version.version);
+    if let Some(from) = args.cmd.from {
+        versions.retain(|version, _| *version == from);
+        if versions.is_empty() {
+            return Err(eyre!(
+                "Version {} of {} is not in the history",
+                from,
+                name.display()
+            ))
+            .with_suggestion(|| {
+                format!(
+                    "`cache` keeps a copy of every manifest in {}",
+                    history_dir.display()
+                )
+            });
+        }
+    } else if versions.is_empty() {
+        log::warn!("No older versions in {}", history_dir.display());
+    }
+
+    let mut stats = Stats::default();
+    for path in versions.values() {
+        let old = load_manifest(path)?;
+        let update = compare(&old, &latest, &archives);
+        stats.updates.push(update);
+    }
+
+    Ok(stats)
+}
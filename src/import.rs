@@ -0,0 +1,194 @@
+//! This tool reverse-engineers a TOML pack config from
+//! an existing package index and a manifest, so that the
+//! `pki` command reproduces the same archive assignment.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use argh::FromArgs;
+use assembly_pack::crc::calculate_crc;
+use color_eyre::eyre::Context;
+use indexmap::IndexMap;
+
+use crate::{
+    load_manifest, load_pack_index,
+    pki::{self, is_compressed, Cfg, PackConfig},
+    ProjectArgs,
+};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// generate a TOML pack config from an existing PKI file
+#[argh(subcommand, name = "import")]
+pub struct Args {
+    /// path to the PKI file (default: the project PKI in the cache dir)
+    #[argh(option)]
+    pub pki: Option<PathBuf>,
+
+    /// path to the manifest used to resolve names (default: the project manifest)
+    #[argh(option, short = 'm')]
+    pub manifest: Option<PathBuf>,
+
+    /// file to write the config to (default: stdout)
+    #[argh(option, short = 'o')]
+    pub output: Option<PathBuf>,
+}
+
+/// A directory of the `res` tree, annotated with the pack of every file
+#[derive(Default, Debug)]
+struct DirNode {
+    dirs: BTreeMap<String, DirNode>,
+    files: Vec<(String, Option<usize>)>,
+}
+
+impl DirNode {
+    fn insert(&mut self, path: &str, pack: Option<usize>) {
+        match path.split_once('\\') {
+            Some((dir, rest)) => self
+                .dirs
+                .entry(dir.to_owned())
+                .or_default()
+                .insert(rest, pack),
+            None => self.files.push((path.to_owned(), pack)),
+        }
+    }
+
+    /// The set of packs that the files in this tree belong to
+    fn packs(&self, set: &mut BTreeSet<Option<usize>>) {
+        set.extend(self.files.iter().map(|(_, pack)| *pack));
+        for node in self.dirs.values() {
+            node.packs(set);
+        }
+    }
+
+    /// Add this directory to the configs, preferring whole directories over single files
+    fn emit(&self, path: &str, configs: &mut [PackConfig]) {
+        let mut set = BTreeSet::new();
+        self.packs(&mut set);
+        if !path.is_empty() && set.len() == 1 {
+            if let Some(Some(pack)) = set.into_iter().next() {
+                configs[pack].dirs.push(path.to_owned());
+            }
+            return;
+        }
+        for (name, pack) in &self.files {
+            if let Some(pack) = pack {
+                configs[*pack].files.push(win_join(path, name));
+            }
+        }
+        for (name, node) in &self.dirs {
+            node.emit(&win_join(path, name), configs);
+        }
+    }
+}
+
+fn win_join(base: &str, name: &str) -> String {
+    match base {
+        "" => name.to_owned(),
+        _ => format!("{}\\{}", base, name),
+    }
+}
+
+/// Get the key of a pack config from the path of an archive
+fn pack_key(archive: &str, pack_prefix: &str) -> String {
+    if let Some(key) = pki::pack_key(archive, pack_prefix) {
+        return key.to_owned();
+    }
+    let name = archive.rsplit('\\').next().unwrap_or(archive);
+    log::warn!(
+        "Archive {} is not in {}, using {} instead",
+        archive,
+        pack_prefix,
+        name
+    );
+    name.strip_suffix(".pk").unwrap_or(name).to_owned()
+}
+
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
+    let paths = args.paths()?;
+
+    let pack_index = match &args.cmd.pki {
+        Some(path) => load_pack_index(path)?,
+        None => load_pack_index(&args.pki_path(&paths))?,
+    };
+    let manifest = match &args.cmd.manifest {
+        Some(path) => load_manifest(path)?,
+        None => load_manifest(&args.manifest_path(&paths))?,
+    };
+
+    let res_prefix = paths.res_prefix_path();
+    let pack_prefix = format!("{}pack\\", res_prefix);
+    let mut configs = vec![PackConfig::default(); pack_index.archives.len()];
+    let mut compressed = vec![(0usize, 0usize); pack_index.archives.len()];
+
+    let mut root = DirNode::default();
+    let mut resolved = 0;
+    for name in manifest.files.keys() {
+        let Some(path) = name.strip_prefix(&res_prefix) else {
+            continue;
+        };
+        let crc = calculate_crc(name.as_bytes());
+        let lookup = pack_index.files.get(&crc);
+        let lookup = lookup.filter(|l| (l.pack_file as usize) < configs.len());
+        let pack = lookup.map(|lookup| {
+            let pk_id = lookup.pack_file as usize;
+            let counts = &mut compressed[pk_id];
+            match is_compressed(lookup) {
+                true => counts.0 += 1,
+                false => counts.1 += 1,
+            }
+            pk_id
+        });
+        if pack.is_some() {
+            resolved += 1;
+        }
+        root.insert(path, pack);
+    }
+    root.emit("", &mut configs);
+
+    if resolved < pack_index.files.len() {
+        log::warn!(
+            "{} of {} files in the PKI are not in the manifest and were skipped",
+            pack_index.files.len() - resolved,
+            pack_index.files.len()
+        );
+    }
+
+    let mut pack: IndexMap<String, PackConfig> = IndexMap::new();
+    for ((archive, mut config), (yes, no)) in
+        pack_index.archives.iter().zip(configs).zip(compressed)
+    {
+        if yes > 0 && no > 0 {
+            log::warn!(
+                "Archive {} has {} compressed and {} uncompressed files",
+                archive.path,
+                yes,
+                no
+            );
+        }
+        config.compress = yes > no;
+        let key = pack_key(&archive.path, &pack_prefix);
+        match pack.get_mut(&key) {
+            Some(existing) => {
+                log::warn!("Duplicate pack name {}, archives were merged", key);
+                existing.dirs.append(&mut config.dirs);
+                existing.files.append(&mut config.files);
+            }
+            None => {
+                pack.insert(key, config);
+            }
+        }
+    }
+
+    let text = toml::to_string(&Cfg { pack }).context("Failed to serialize pack config")?;
+    match &args.cmd.output {
+        Some(path) => {
+            log::info!("Writing to {}", path.display());
+            std::fs::write(path, text)
+                .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        }
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
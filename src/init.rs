@@ -0,0 +1,212 @@
+//! This tool scaffolds a new workspace from an existing
+//! client directory, writing an `LUpdate.toml` and a
+//! starter pack config.
+use std::{
+    ffi::OsStr,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use argh::FromArgs;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Help,
+};
+use indexmap::IndexMap;
+
+use crate::pki::{Cfg, PackConfig};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// create an LUpdate.toml and pack config for a client directory
+#[argh(subcommand, name = "init")]
+pub struct Args {
+    /// the client directory to set up
+    #[argh(positional)]
+    pub client: PathBuf,
+
+    /// name of the project (default: name of the client directory)
+    #[argh(option, short = 'n')]
+    pub name: Option<String>,
+
+    /// overwrite existing config files
+    #[argh(switch, short = 'f')]
+    pub force: bool,
+}
+
+/// What `init` found in the client directory
+#[derive(Debug, Default)]
+struct Inspection {
+    /// Name of the `res` dir, empty if the client dir is used directly
+    res: String,
+    /// Name of a pack config (TOML or legacy text) that already exists
+    pack_config: Option<&'static str>,
+    /// Stems of the PK archives in `res/pack`
+    pk_files: Vec<String>,
+    /// Top-level directories in `res`
+    res_dirs: Vec<String>,
+}
+
+fn list_dir(dir: &Path, want_dir: bool) -> Vec<String> {
+    let Ok(rd) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = rd
+        .filter_map(Result::ok)
+        .filter(|e| {
+            e.file_type()
+                .map(|t| t.is_dir() == want_dir)
+                .unwrap_or(false)
+        })
+        .filter_map(|e| e.file_name().to_str().map(str::to_owned))
+        .collect();
+    names.sort();
+    names
+}
+
+fn inspect(client: &Path) -> Inspection {
+    let res = match client.join("res").is_dir() {
+        true => String::from("res"),
+        false => String::new(),
+    };
+    let res_dir = client.join(&res);
+    let pack_config = ["config.toml", "config.txt"]
+        .into_iter()
+        .find(|name| client.join(name).is_file());
+    let pk_files = list_dir(&res_dir.join("pack"), false)
+        .into_iter()
+        .filter(|name| Path::new(name).extension() == Some(OsStr::new("pk")))
+        .map(|name| name[..name.len() - 3].to_owned())
+        .collect();
+    let res_dirs = list_dir(&res_dir, true)
+        .into_iter()
+        .filter(|name| !name.eq_ignore_ascii_case("pack"))
+        .collect();
+    Inspection {
+        res,
+        pack_config,
+        pk_files,
+        res_dirs,
+    }
+}
+
+fn starter_pack_config(found: &Inspection) -> Cfg {
+    let mut pack = IndexMap::new();
+    if found.pk_files.is_empty() {
+        for dir in &found.res_dirs {
+            let cfg = PackConfig {
+                dirs: vec![dir.clone()],
+                ..Default::default()
+            };
+            pack.insert(dir.to_lowercase(), cfg);
+        }
+    } else {
+        // We can't know the contents of existing archives without a PKI,
+        // so just reserve the names and leave the rest to the user.
+        for name in &found.pk_files {
+            pack.insert(name.clone(), PackConfig::default());
+        }
+    }
+    Cfg { pack }
+}
+
+fn toml_str(value: &str) -> String {
+    toml::Value::String(value.to_owned()).to_string()
+}
+
+fn toml_key(key: &str) -> String {
+    match key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        true => key.to_owned(),
+        false => toml_str(key),
+    }
+}
+
+fn workspace_config(src: &str, name: &str, dir: &str, found: &Inspection, config: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "[general]");
+    let _ = writeln!(out, "src = {}", toml_str(src));
+    let _ = writeln!(out);
+    let _ = writeln!(out, "[project.{}]", toml_key(name));
+    if dir != name {
+        let _ = writeln!(out, "dir = {}", toml_str(dir));
+    }
+    let _ = writeln!(out, "config = {}", toml_str(config));
+    if found.res != "res" {
+        let _ = writeln!(out, "res = {}", toml_str(&found.res));
+    }
+    out
+}
+
+fn check_new(path: &Path, force: bool) -> color_eyre::Result<()> {
+    if path.exists() && !force {
+        return Err(eyre!("{} already exists", path.display()))
+            .with_suggestion(|| "Use `--force` to overwrite it");
+    }
+    Ok(())
+}
+
+fn write_new(path: &Path, text: &str, force: bool) -> color_eyre::Result<()> {
+    check_new(path, force)?;
+    log::info!("Writing {}", path.display());
+    std::fs::write(path, text).wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
+
+pub fn run(args: Args, config_path: &Path) -> color_eyre::Result<()> {
+    check_new(config_path, args.force)?;
+    let root = match config_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let root = root
+        .canonicalize()
+        .wrap_err_with(|| format!("Failed to find workspace dir {}", root.display()))?;
+    let client = args
+        .client
+        .canonicalize()
+        .wrap_err_with(|| format!("Failed to find client dir {}", args.client.display()))?;
+    if !client.is_dir() {
+        return Err(eyre!("{} is not a directory", client.display()));
+    }
+
+    let dir = client
+        .file_name()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| eyre!("Client dir {} has no UTF-8 name", client.display()))?;
+    let name = args.name.as_deref().unwrap_or(dir);
+    let parent = client.parent().unwrap_or(&client);
+    let src = parent.strip_prefix(&root).unwrap_or(parent);
+    let src = match src.as_os_str().is_empty() {
+        true => ".",
+        false => src
+            .to_str()
+            .ok_or_else(|| eyre!("Source dir {} is not UTF-8", src.display()))?,
+    };
+
+    let found = inspect(&client);
+    log::info!("Using {:?} as the res dir", found.res);
+    log::info!("Found {} PK archives", found.pk_files.len());
+
+    let config = match found.pack_config {
+        Some(config) => {
+            log::info!("Keeping existing pack config {}", config);
+            config
+        }
+        None => {
+            let cfg = starter_pack_config(&found);
+            let text = toml::to_string(&cfg).context("Failed to serialize pack config")?;
+            write_new(&client.join("config.toml"), &text, args.force)?;
+            "config.toml"
+        }
+    };
+
+    let text = workspace_config(&src.replace('\\', "/"), name, dir, &found, config);
+    write_new(config_path, &text, args.force)?;
+
+    let cache = root.join("cache");
+    std::fs::create_dir_all(&cache)
+        .wrap_err_with(|| format!("Failed to create {}", cache.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! # LUpdate
+//!
+//! Library to generate LU-patcher *compatible* cache directories.
+//!
+//! The `lupdate` binary is a thin wrapper around this crate. To run a command
+//! from another tool, load a [`Config`], pick a project and call the `run`
+//! function of the respective module with a [`ProjectArgs`]:
+//!
+//! ```no_run
+//! use lupdate::{cache, config::Config, ProjectArgs};
+//!
+//! # fn main() -> color_eyre::Result<()> {
+//! let config = Config::from_file("LUpdate.toml")?;
+//! let project = &config.project["client"];
+//! let cmd = cache::Args::default();
+//! let args = ProjectArgs::new(".".into(), config.general.clone(), project, "client", cmd);
+//! let stats = cache::run(args)?;
+//! println!("{} files", stats.total);
+//! # Ok(())
+//! # }
+//! ```
+use std::path::{Path, PathBuf};
+
+use assembly_pack::{pki::core::PackIndexFile, txt::Manifest};
+use color_eyre::eyre::{eyre, Context};
+
+pub mod cache;
+pub mod config;
+pub mod dedup;
+pub mod download;
+pub mod import;
+pub mod init;
+pub mod pack;
+pub mod patcher;
+pub mod pki;
+pub mod progress;
+pub mod stats;
+
+pub use config::Config;
+use config::{GeneralConfig, ProjectConfig};
+
+#[derive(PartialEq, Debug)]
+/// arguments with a project
+pub struct ProjectArgs<'a, A> {
+    dir: PathBuf,
+    general: GeneralConfig,
+    project: &'a ProjectConfig,
+    name: &'a str,
+    cmd: A,
+}
+
+impl<'a, A> ProjectArgs<'a, A> {
+    pub fn new(
+        dir: PathBuf,
+        general: GeneralConfig,
+        project: &'a ProjectConfig,
+        name: &'a str,
+        cmd: A,
+    ) -> Self {
+        Self {
+            dir,
+            general,
+            project,
+            name,
+            cmd,
+        }
+    }
+
+    /// The name of the project
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// The command specific arguments
+    pub fn cmd(&self) -> &A {
+        &self.cmd
+    }
+
+    /// Resolve the directories and prefixes of the project
+    pub fn paths(&self) -> color_eyre::Result<Paths> {
+        let res_name = self.project.res.as_ref().unwrap_or(&self.general.res);
+        let src = self.project.src.as_deref().unwrap_or(&self.general.src);
+        let src_dir = self.dir.join(src);
+        let dir = self.project.dir.as_deref().unwrap_or(self.name);
+        let proj_dir = src_dir.join(dir);
+        let res_dir = match res_name.as_str() {
+            "" => proj_dir.clone(),
+            path => proj_dir.join(path),
+        };
+        let dir_name = proj_dir
+            .file_name()
+            .ok_or_else(|| eyre!("Project dir {} has no name", proj_dir.display()))?
+            .to_str()
+            .ok_or_else(|| eyre!("Project dir {} is not UTF-8", proj_dir.display()))?;
+
+        let prefix = self
+            .project
+            .prefix
+            .as_deref()
+            .unwrap_or(dir_name)
+            .replace('/', "\\");
+        let strip_prefix = match prefix.as_str() {
+            "" => String::new(),
+            path => format!("{path}\\"),
+        };
+        let res_prefix = {
+            let mut p = prefix.clone();
+            if !res_name.is_empty() {
+                for part in res_name.split(&['/', '\\']) {
+                    if !p.is_empty() {
+                        p.push('\\');
+                    }
+                    p.push_str(part);
+                }
+            }
+            p
+        };
+        // Cache dir
+        let cache_key = self.project.key.as_deref().unwrap_or(self.name);
+        let cache_dir_parent = self.dir.join(&self.project.cache);
+        let cache_dir = cache_dir_parent.join(cache_key);
+        Ok(Paths {
+            proj_dir,
+            cache_dir,
+            cache_dir_parent,
+            res_dir,
+            prefix,
+            strip_prefix,
+            res_prefix,
+        })
+    }
+
+    /// The project manifest in the cache dir
+    pub(crate) fn manifest_path(&self, paths: &Paths) -> PathBuf {
+        paths
+            .cache_dir
+            .join(&self.project.manifest)
+            .with_extension("txt")
+    }
+
+    /// The project PKI in the cache dir
+    pub(crate) fn pki_path(&self, paths: &Paths) -> PathBuf {
+        paths
+            .cache_dir
+            .join(&self.project.pki)
+            .with_extension("pki")
+    }
+
+    /// Load the manifest and the PKI, if there is one
+    ///
+    /// The paths default to the files of the project. If no PKI is given,
+    /// a missing project PKI is not an error.
+    pub(crate) fn load_manifest_and_pki(
+        &self,
+        paths: &Paths,
+        manifest: Option<&Path>,
+        pki: Option<&Path>,
+    ) -> color_eyre::Result<(Manifest, Option<PackIndexFile>)> {
+        let manifest = match manifest {
+            Some(path) => load_manifest(path)?,
+            None => load_manifest(&self.manifest_path(paths))?,
+        };
+        let pki_path = match pki {
+            Some(path) => Some(path.to_owned()),
+            None => Some(self.pki_path(paths)).filter(|p| p.is_file()),
+        };
+        let pack_index = match &pki_path {
+            Some(path) => Some(load_pack_index(path)?),
+            None => {
+                log::info!("No pack index, skipping the PK archives");
+                None
+            }
+        };
+        Ok((manifest, pack_index))
+    }
+}
+
+/// Load a manifest
+pub(crate) fn load_manifest(path: &Path) -> color_eyre::Result<Manifest> {
+    log::info!("manifest: {}", path.display());
+    Manifest::from_file(path).wrap_err_with(|| format!("Failed to load {}", path.display()))
+}
+
+/// Load a PKI
+pub(crate) fn load_pack_index(path: &Path) -> color_eyre::Result<PackIndexFile> {
+    log::info!("pack index: {}", path.display());
+    PackIndexFile::from_file(path).wrap_err_with(|| format!("Failed to load {}", path.display()))
+}
+
+#[derive(Debug)]
+/// The resolved directories and prefixes of a project
+pub struct Paths {
+    /// The root directory of the project
+    pub proj_dir: PathBuf,
+    /// The cache dir of the project, i.e. `{cache}/{key}`
+    pub cache_dir: PathBuf,
+    /// The cache dir shared by all projects, i.e. `{cache}`
+    pub cache_dir_parent: PathBuf,
+    /// The prefix of all paths in the manifest, e.g. `client`
+    pub prefix: String,
+    /// Prefix with a trailing slash (if not empty)
+    pub strip_prefix: String,
+    /// The `res` dir that is considered for packing
+    pub res_dir: PathBuf,
+    /// The prefix of all paths in the `res` dir, e.g. `client\res`
+    pub res_prefix: String,
+}
+
+impl Paths {
+    /// The `res` prefix with a trailing slash (if not empty)
+    pub fn res_prefix_path(&self) -> String {
+        match self.res_prefix.as_str() {
+            "" => String::new(),
+            path => format!("{path}\\"),
+        }
+    }
+
+    /// Lowercase a manifest path, keeping the project prefix as it is
+    pub fn lowercase(&self, path: &str) -> String {
+        lowercase_path(&self.strip_prefix, path)
+    }
+}
+
+pub(crate) fn lowercase_path(prefix: &str, path: &str) -> String {
+    match path.strip_prefix(prefix) {
+        Some(rest) => format!("{}{}", prefix, rest.to_lowercase()),
+        None => path.to_lowercase(),
+    }
+}
+
+/// Join a backslash separated path to `base`
+pub(crate) fn win_join(base: &Path, path: &str) -> PathBuf {
+    path.split('\\').fold(base.to_owned(), |mut l, r| {
+        l.push(r);
+        l
+    })
+}
+
+/// Like [`win_join`], but matches each part case-insensitively if it doesn't exist
+///
+/// Used to find the files of lowercased paths on disk.
+pub(crate) fn win_join_nocase(base: &Path, path: &str) -> PathBuf {
+    path.split('\\').fold(base.to_owned(), |mut l, r| {
+        let exact = l.join(r);
+        if exact.exists() {
+            return exact;
+        }
+        let found = std::fs::read_dir(&l).ok().and_then(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name())
+                .find(|name| {
+                    name.to_str()
+                        .is_some_and(|name| name.eq_ignore_ascii_case(r))
+                })
+        });
+        l.push(found.as_deref().unwrap_or_else(|| r.as_ref()));
+        l
+    })
+}
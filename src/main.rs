@@ -1,14 +1,23 @@
-use std::path::PathBuf;
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use argh::FromArgs;
-use color_eyre::{eyre::eyre, Help};
-use config::{Config, GeneralConfig, ProjectConfig};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Help,
+};
+use globset::{Glob, GlobSetBuilder};
+use indicatif::HumanBytes;
+use indicatif_log_bridge::LogWrapper;
 use log::LevelFilter;
-
-mod cache;
-mod config;
-mod pack;
-mod pki;
+use lupdate::{
+    cache,
+    config::{find_config_file, Config, GeneralConfig, ProjectConfig, CONFIG_FILE},
+    dedup, download, import, init, pack, patcher, pki, progress, stats, ProjectArgs,
+};
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// CLI to update a patch server
@@ -16,153 +25,329 @@ struct Args {
     #[argh(subcommand)]
     nested: Commands,
     #[argh(option, short = 'p')]
-    /// select a specific project
-    project: Option<String>,
+    /// select a specific project (name or glob, may be repeated)
+    project: Vec<String>,
+    #[argh(switch, short = 'a')]
+    /// select all projects
+    all: bool,
+    #[argh(option, short = 'c')]
+    /// path to the config file (default: nearest `LUpdate.toml`)
+    config: Option<PathBuf>,
 }
 
-#[derive(FromArgs, PartialEq, Debug)]
+#[derive(FromArgs, PartialEq, Debug, Clone)]
 #[argh(subcommand)]
 pub enum Commands {
     Cache(cache::Args),
     Pack(pack::Args),
     PKI(pki::Args),
+    Patcher(patcher::Args),
+    Init(init::Args),
+    Import(import::Args),
+    Dedup(dedup::Args),
+    Stats(stats::Args),
+    Download(download::Args),
 }
 
-#[derive(PartialEq, Debug)]
-/// arguments with a project
-pub struct ProjectArgs<'a, A> {
-    dir: PathBuf,
-    general: GeneralConfig,
-    project: &'a ProjectConfig,
-    name: &'a str,
-    cmd: A,
-}
-
-impl<'a, A> ProjectArgs<'a, A> {
-    pub fn new(
+impl Commands {
+    fn run(
+        self,
         dir: PathBuf,
         general: GeneralConfig,
-        project: &'a ProjectConfig,
-        name: &'a str,
-        cmd: A,
-    ) -> Self {
-        Self {
-            dir,
-            general,
-            project,
-            name,
-            cmd,
+        project: &ProjectConfig,
+        name: &str,
+    ) -> color_eyre::Result<()> {
+        match self {
+            Self::Cache(cmd) => {
+                let stats = cache::run(ProjectArgs::new(dir, general, project, name, cmd))?;
+                log::info!("{:?}", stats);
+            }
+            Self::Pack(cmd) => {
+                let verb = match cmd.verify {
+                    true => "Verified",
+                    false => "Packed",
+                };
+                let stats = pack::run(ProjectArgs::new(dir, general, project, name, cmd))?;
+                log::info!(
+                    "{} {} files in {} archives",
+                    verb,
+                    stats.files,
+                    stats.archives.len()
+                );
+                if stats.computed > 0 {
+                    log::info!("Computed the metadata of {} files", stats.computed);
+                }
+                if stats.stale > 0 {
+                    log::warn!("{} files didn't match the manifest", stats.stale);
+                }
+            }
+            Self::PKI(cmd) => {
+                let pki = pki::run(ProjectArgs::new(dir, general, project, name, cmd))?;
+                log::info!("number of archives: {}", pki.archives.len());
+                log::info!("number of files: {}", pki.files.len());
+            }
+            Self::Patcher(cmd) => patcher::run(ProjectArgs::new(dir, general, project, name, cmd))?,
+            Self::Import(cmd) => import::run(ProjectArgs::new(dir, general, project, name, cmd))?,
+            Self::Dedup(cmd) => {
+                let stats = dedup::run(ProjectArgs::new(dir, general, project, name, cmd))?;
+                print_dedup(&stats);
+                log::info!(
+                    "{} groups of identical files, {} saved in the cache, {} wasted in PKs",
+                    stats.groups.len(),
+                    HumanBytes(stats.saved),
+                    HumanBytes(stats.wasted)
+                );
+            }
+            Self::Stats(cmd) => {
+                let stats = stats::run(ProjectArgs::new(dir, general, project, name, cmd))?;
+                print_stats(&stats);
+                log::info!(
+                    "{} files, {} in {} archives",
+                    stats.total.files,
+                    HumanBytes(stats.total.raw),
+                    stats.archives.len()
+                );
+            }
+            Self::Download(cmd) => {
+                // The files are only listed for a single version
+                let top = cmd.from.map(|_| cmd.top);
+                let stats = download::run(ProjectArgs::new(dir, general, project, name, cmd))?;
+                print_download(&stats, top.unwrap_or(0));
+                if let Some(largest) = stats.updates.iter().max_by_key(|u| u.bytes) {
+                    log::info!(
+                        "Largest update: {} from v{}",
+                        HumanBytes(largest.bytes),
+                        largest.from
+                    );
+                }
+            }
+            Self::Init(_) => return Err(eyre!("`init` does not run on a project")),
         }
+        Ok(())
     }
+}
 
-    pub fn paths(&self) -> Paths {
-        let res_name = self.project.res.as_ref().unwrap_or(&self.general.res);
-        let src = self.project.src.as_deref().unwrap_or(&self.general.src);
-        let src_dir = self.dir.join(&src);
-        let dir = self.project.dir.as_deref().unwrap_or(self.name);
-        let proj_dir = src_dir.join(&dir);
-        let res_dir = match res_name.as_str() {
-            "" => proj_dir.clone(),
-            path => proj_dir.join(path),
-        };
-        let dir_name = proj_dir
-            .file_name()
-            .expect("project dir to have a name")
-            .to_str()
-            .expect("project dir have ASCII name");
-
-        let prefix = self
-            .project
-            .prefix
-            .as_deref()
-            .unwrap_or(dir_name)
-            .replace('/', "\\");
-        let strip_prefix = match prefix.as_str() {
-            "" => String::new(),
-            path => format!("{path}\\"),
-        };
-        let res_prefix = {
-            let mut p = prefix.clone();
-            if !res_name.is_empty() {
-                for part in res_name.split(&['/', '\\']) {
-                    if !p.is_empty() {
-                        p.push('\\');
-                    }
-                    p.push_str(part);
-                }
+/// Print the groups of a [`dedup::run`] to stdout
+fn print_dedup(stats: &dedup::Stats) {
+    for group in &stats.groups {
+        println!(
+            "{:?}: {} files of {}, {} saved in the cache, {} wasted in PKs",
+            group.meta.raw.hash,
+            group.files.len(),
+            HumanBytes(group.meta.raw.size.into()),
+            HumanBytes(group.saved()),
+            HumanBytes(group.wasted())
+        );
+        for file in &group.files {
+            match &file.archive {
+                Some(archive) => println!("    {} ({})", file.path, archive),
+                None => println!("    {}", file.path),
             }
-            p
-        };
-        // Cache dir
-        let cache_key = self.project.key.as_deref().unwrap_or(self.name);
-        let cache_dir_parent = self.dir.join(&self.project.cache);
-        let cache_dir = cache_dir_parent.join(&cache_key);
-        Paths {
-            proj_dir,
-            cache_dir,
-            cache_dir_parent,
-            res_dir,
-            prefix,
-            strip_prefix,
-            res_prefix,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Paths {
-    proj_dir: PathBuf,
-    cache_dir: PathBuf,
-    cache_dir_parent: PathBuf,
-    prefix: String,
-    // Prefix with a trailing slash (if not empty)
-    strip_prefix: String,
-    res_dir: PathBuf,
-    res_prefix: String,
+fn print_totals(title: &str, groups: &BTreeMap<String, stats::Totals>) {
+    println!("{}:", title);
+    let mut groups: Vec<_> = groups.iter().collect();
+    groups.sort_by_key(|(_, totals)| Reverse(totals.raw));
+    for (name, totals) in groups {
+        println!(
+            "    {}: {} files, {} -> {} ({:.1}%)",
+            name,
+            totals.files,
+            HumanBytes(totals.raw),
+            HumanBytes(totals.compressed),
+            totals.ratio() * 100.0
+        );
+    }
 }
 
-impl Paths {
-    fn res_prefix_path(&self) -> String {
-        match self.res_prefix.as_str() {
-            "" => String::new(),
-            path => format!("{path}\\"),
+/// Print the report of a [`stats::run`] to stdout
+fn print_stats(stats: &stats::Stats) {
+    println!(
+        "Total: {} files, {} -> {} ({:.1}%)",
+        stats.total.files,
+        HumanBytes(stats.total.raw),
+        HumanBytes(stats.total.compressed),
+        stats.total.ratio() * 100.0
+    );
+    print_totals("By extension", &stats.by_extension);
+    print_totals("By directory", &stats.by_dir);
+    println!("Largest files:");
+    for (path, meta) in &stats.largest {
+        println!(
+            "    {}: {} -> {}",
+            path,
+            HumanBytes(meta.raw.size.into()),
+            HumanBytes(meta.compressed.size.into())
+        );
+    }
+    if !stats.archives.is_empty() {
+        println!("Archives:");
+    }
+    for archive in &stats.archives {
+        let size = match archive.size {
+            Some(size) => HumanBytes(size).to_string(),
+            None => "not in the manifest".to_string(),
+        };
+        println!(
+            "    {}: {} files, {} of content, {}",
+            archive.path,
+            archive.files,
+            HumanBytes(archive.content),
+            size
+        );
+    }
+}
+
+/// Print the updates of a [`download::run`] and the `top` largest files of each to stdout
+fn print_download(stats: &download::Stats, top: usize) {
+    for update in &stats.updates {
+        let packed = update.files.iter().filter(|f| f.archive).count();
+        println!(
+            "v{} -> v{}: {} ({} in {} archives, {} loose files), {} removed",
+            update.from,
+            update.to,
+            HumanBytes(update.bytes),
+            HumanBytes(update.archive_bytes()),
+            packed,
+            update.files.len() - packed,
+            update.removed
+        );
+        for file in update.files.iter().take(top) {
+            let kind = match (file.archive, file.new) {
+                (true, _) => "archive",
+                (false, true) => "new",
+                (false, false) => "changed",
+            };
+            println!(
+                "    {} ({}): {}",
+                file.path,
+                kind,
+                HumanBytes(file.size.into())
+            );
+        }
+    }
+}
+
+/// Find the projects selected by `--all` / `-p`
+fn select_projects<'c>(
+    config: &'c Config,
+    args: &Args,
+) -> color_eyre::Result<Vec<(&'c String, &'c ProjectConfig)>> {
+    if args.all {
+        return Ok(config.project.iter().collect());
+    }
+    if args.project.is_empty() {
+        return match config.project.len() {
+            1 => Ok(config.project.iter().collect()),
+            _ => Err(eyre!("More than one project found!")).with_suggestion(|| {
+                "Please specify using `-p <name>` or select all with `--all`".to_string()
+            }),
+        };
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &args.project {
+        let glob = Glob::new(pattern)
+            .wrap_err_with(|| format!("Invalid project pattern {:?}", pattern))?;
+        if !config
+            .project
+            .keys()
+            .any(|k| glob.compile_matcher().is_match(k))
+        {
+            return Err(eyre!("Project {:?} not found!", pattern));
         }
+        builder.add(glob);
     }
+    let set = builder.build()?;
+    Ok(config
+        .project
+        .iter()
+        .filter(|(k, _)| set.is_match(k.as_str()))
+        .collect())
 }
 
 fn main() -> color_eyre::Result<()> {
-    env_logger::builder()
+    let logger = env_logger::builder()
         .format_timestamp(None)
         .filter_level(LevelFilter::Info)
         .parse_default_env()
         .filter_module("globset", LevelFilter::Info)
-        .init();
+        .build();
+    let level = logger.filter();
+    // Print log lines above the progress bars
+    LogWrapper::new(progress::multi().clone(), logger)
+        .try_init()
+        .context("Failed to set up logging")?;
+    log::set_max_level(level);
 
     let args: Args = argh::from_env();
-    //let dir = std::env::current_dir().wrap_err("Failed to get current directory")?;
-    let dir = PathBuf::from(".");
-    let config = Config::from_file(dir.join("LUpdate.toml"))?;
-
-    let (name, project) = if let Some(key) = args.project.as_ref() {
-        if let Some(p) = config.project.get(key) {
-            (key, p)
-        } else {
-            return Err(eyre!("Project {:?} not found!", key));
+    if let Commands::Init(cmd) = args.nested {
+        let config_path = match args.config {
+            Some(path) if path.is_dir() => path.join(CONFIG_FILE),
+            Some(path) => path,
+            None => PathBuf::from(CONFIG_FILE),
+        };
+        return init::run(cmd, &config_path);
+    }
+
+    let config_path = match &args.config {
+        Some(path) if path.is_dir() => path.join(CONFIG_FILE),
+        Some(path) => path.clone(),
+        None => {
+            let cwd = std::env::current_dir().wrap_err("Failed to get current directory")?;
+            find_config_file(&cwd)
+                .ok_or_else(|| eyre!("Could not find {} in {}", CONFIG_FILE, cwd.display()))
+                .with_suggestion(|| "Run from within a workspace or pass `--config <path>`")?
         }
-    } else if config.project.len() == 1 {
-        config.project.iter().next().unwrap()
-    } else {
-        return Err(eyre!("More than one project found!"))
-            .with_suggestion(|| "Please specify using `-p <name>".to_string());
     };
+    log::debug!("Using config {}", config_path.display());
+    // All paths in the config are relative to the directory of the config file
+    let dir = config_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let config = Config::from_file(&config_path)?;
 
-    log::info!("Using project {:?}", name);
+    let projects = select_projects(&config, &args)?;
+    if let [(name, project)] = projects.as_slice() {
+        log::info!("Using project {:?}", name);
+        return args.nested.run(dir, config.general.clone(), project, name);
+    }
+    // Watching never returns, so the other projects would never run
+    if let Commands::Cache(cmd) = &args.nested {
+        if cmd.watch {
+            return Err(eyre!(
+                "`--watch` needs a single project, but {} were selected",
+                projects.len()
+            ))
+            .with_suggestion(|| "Select one project with `-p <name>`");
+        }
+    }
 
-    match args.nested {
-        Commands::Cache(cmd) => {
-            cache::run(ProjectArgs::new(dir, config.general, project, name, cmd))
+    let mut failed = Vec::new();
+    for (name, project) in &projects {
+        log::info!("Using project {:?}", name);
+        let cmd = args.nested.clone();
+        match cmd.run(dir.clone(), config.general.clone(), project, name) {
+            Ok(()) => log::info!("Project {:?} done", name),
+            Err(e) => {
+                log::error!("Project {:?} failed: {:?}", name, e);
+                failed.push(name.as_str());
+            }
         }
-        Commands::Pack(cmd) => pack::run(ProjectArgs::new(dir, config.general, project, name, cmd)),
-        Commands::PKI(cmd) => pki::run(ProjectArgs::new(dir, config.general, project, name, cmd)),
+    }
+
+    log::info!(
+        "{} of {} projects succeeded",
+        projects.len() - failed.len(),
+        projects.len()
+    );
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!("Projects failed: {}", failed.join(", ")))
     }
 }
@@ -0,0 +1,586 @@
+//! This tool is used to pre-package PK files given
+//! a patcher dir with sd0 files, a trunk manifest file
+//! and a package index.
+//!
+//! It outputs a filtered
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufReader, ErrorKind},
+    num::NonZeroUsize,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use argh::FromArgs;
+use assembly_pack::{
+    common::{
+        fs::{scan_dir, FileInfo, FsVisitor},
+        FileMeta, FileMetaPair,
+    },
+    crc::calculate_crc,
+    md5,
+    pk::fs::{PKHandle, PKWriter},
+    pki::core::PackIndexFile,
+};
+use color_eyre::eyre::{eyre, Context};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use indicatif::ProgressBar;
+
+use crate::{
+    cache::Converter, load_manifest, load_pack_index, pki, progress, win_join, win_join_nocase,
+    Paths, ProjectArgs,
+};
+
+mod verify;
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// pack files into PK archives
+#[argh(subcommand, name = "pack")]
+pub struct Args {
+    #[argh(option, short = 'f')]
+    /// glob on the archive path or pack key to include (may be repeated, default: all)
+    pub filter: Vec<String>,
+
+    #[argh(option, short = 'x')]
+    /// glob on the archive path or pack key to exclude (may be repeated)
+    pub exclude: Vec<String>,
+
+    /// number of archives to write in parallel (0: one per CPU core)
+    #[argh(option, short = 'j', default = "1")]
+    pub jobs: usize,
+
+    /// check the existing archives against the PKI and manifest instead of writing them
+    #[argh(switch)]
+    pub verify: bool,
+
+    /// keep writing the other archives if one fails
+    #[argh(switch)]
+    pub keep_going: bool,
+
+    /// what to do if a file doesn't match the manifest: fail, warn or refresh (default: fail)
+    #[argh(option, default = "Stale::Fail")]
+    pub stale: Stale,
+
+    /// also compare the MD5 hashes of the files with the manifest, not just their sizes
+    #[argh(switch)]
+    pub check_hash: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            filter: Vec::new(),
+            exclude: Vec::new(),
+            jobs: 1,
+            verify: false,
+            keep_going: false,
+            stale: Stale::Fail,
+            check_hash: false,
+        }
+    }
+}
+
+/// How `pack` handles files that changed since the manifest was written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stale {
+    /// Stop with an error
+    Fail,
+    /// Log a warning and pack the file anyway
+    Warn,
+    /// Compute the metadata again, like for files that are not in the manifest
+    Refresh,
+}
+
+impl FromStr for Stale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "warn" => Ok(Self::Warn),
+            "refresh" => Ok(Self::Refresh),
+            _ => Err(format!("expected fail, warn or refresh, got {:?}", s)),
+        }
+    }
+}
+
+struct Writer<'a> {
+    path: &'a Path,
+}
+
+impl<'a> PKWriter for Writer<'a> {
+    fn write<W: std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        let file = File::open(self.path)?;
+        let mut reader = BufReader::new(file);
+        std::io::copy(&mut reader, writer)?;
+        Ok(())
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// A file to be written into an archive
+struct Input {
+    name: String,
+    crc: u32,
+    /// The metadata from the manifest, if there is an entry
+    meta: Option<FileMetaPair>,
+    is_compressed: bool,
+}
+
+/// Collects the files of the selected archives that are not in the manifest
+struct Finder {
+    missing: HashSet<u32>,
+    found: Vec<(String, u32)>,
+}
+
+impl FsVisitor for Finder {
+    fn visit_file(&mut self, info: FileInfo) {
+        let name = info.path();
+        let crc = calculate_crc(name.as_bytes());
+        if self.missing.remove(&crc) {
+            self.found.push((name, crc));
+        }
+    }
+}
+
+/// The sd0 metadata for one raw hash, once it is known
+type Sd0Slot = Arc<Mutex<Option<FileMeta>>>;
+
+/// The state shared by the threads that write the archives
+struct Packer<'a> {
+    paths: &'a Paths,
+    pack_index: &'a PackIndexFile,
+    lowercase: bool,
+    bar: &'a ProgressBar,
+    /// Converter for files that are not in the cache
+    conv: Converter,
+    /// The number of files that were not in the manifest
+    computed: AtomicUsize,
+    /// The sd0 files that were looked up or created by [`Packer::describe`], by raw hash
+    sd0: Mutex<HashMap<[u8; 16], Sd0Slot>>,
+    stale: Stale,
+    check_hash: bool,
+    /// The number of files that didn't match the manifest
+    stale_files: AtomicUsize,
+}
+
+impl Packer<'_> {
+    /// Compute the metadata of a file that is not in the manifest
+    ///
+    /// Like `cache`, this creates the sd0 file in the cache if it doesn't exist,
+    /// because the PK entries of all files record the compressed size and hash.
+    fn describe(&self, name: &str, source: &Path) -> std::io::Result<FileMetaPair> {
+        let raw = md5::md5sum(source)?;
+        // Archives are written in parallel and may contain the same content, so the
+        // sd0 file for each hash is looked up (or created) only once
+        let slot = Arc::clone(self.sd0.lock().unwrap().entry(raw.hash.0).or_default());
+        let mut slot = slot.lock().unwrap();
+        if let Some(compressed) = *slot {
+            return Ok(FileMetaPair { raw, compressed });
+        }
+        let (meta, _) = self.conv.find_or_convert(name, source, raw)?;
+        *slot = Some(meta.compressed);
+        Ok(meta)
+    }
+
+    /// Compare a file that is packed with its manifest entry, returning the problem
+    fn check_file(&self, path: &Path, meta: &FileMeta) -> std::io::Result<Option<String>> {
+        let size = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Some(format!("{} is missing", path.display())))
+            }
+            Err(e) => return Err(e),
+        };
+        if size != u64::from(meta.size) {
+            return Ok(Some(format!(
+                "{} has {} bytes instead of {}",
+                path.display(),
+                size,
+                meta.size
+            )));
+        }
+        if self.check_hash {
+            let actual = md5::md5sum(path)?;
+            if actual.hash != meta.hash {
+                return Ok(Some(format!(
+                    "{} has hash {:?} instead of {:?}",
+                    path.display(),
+                    actual.hash,
+                    meta.hash
+                )));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check the source (and sd0 file) of a manifest entry, handling a mismatch as configured
+    fn check(
+        &self,
+        file: &Input,
+        source: &Path,
+        meta: FileMetaPair,
+    ) -> color_eyre::Result<FileMetaPair> {
+        let mut problem = self.check_file(source, &meta.raw)?;
+        if problem.is_none() && file.is_compressed {
+            let sd0 = self.paths.cache_dir.join(meta.to_path());
+            problem = self.check_file(&sd0, &meta.compressed)?;
+        }
+        let Some(problem) = problem else {
+            return Ok(meta);
+        };
+        self.stale_files.fetch_add(1, Ordering::Relaxed);
+        match self.stale {
+            Stale::Fail => Err(eyre!(
+                "{} changed since the manifest was written: {}\n\
+                 Run `cache` again, or pass `--stale refresh` to update it while packing",
+                file.name,
+                problem
+            )),
+            Stale::Warn => {
+                log::warn!("{} doesn't match the manifest: {}", file.name, problem);
+                Ok(meta)
+            }
+            Stale::Refresh => {
+                log::debug!("Refreshing {}: {}", file.name, problem);
+                Ok(self.describe(&file.name, source)?)
+            }
+        }
+    }
+
+    /// Write a single PK archive, returning the number of files
+    ///
+    /// If this fails, the incomplete archive is removed.
+    fn write_archive(&self, pk_id: usize, files: &[Input]) -> color_eyre::Result<usize> {
+        let paths = self.paths;
+        let archive = &self.pack_index.archives[pk_id].path;
+        let name = self.relative(archive)?;
+
+        // The PKI has the lowercase path, but the dirs on disk may differ in case
+        let path = match self.lowercase {
+            true => win_join_nocase(&paths.proj_dir, name),
+            false => win_join(&paths.proj_dir, name),
+        };
+        log::info!("Opening PK {}", path.display());
+
+        // FIXME: Don't delete, update
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed to create pack dir {}", parent.display()))?;
+        } else {
+            log::warn!("Could not get parent dir for {}", path.display());
+        }
+
+        // Start from an empty file, so that nothing of the old archive is left over
+        File::create(&path).wrap_err_with(|| format!("Failed to create PK {}", path.display()))?;
+
+        let result = self.write_files(&path, name, files);
+        match &result {
+            Ok(_) => log::info!("Closing out PK {}", archive),
+            Err(_) => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        result
+    }
+
+    /// Strip the prefix of the project from a path in the PKI
+    fn relative<'b>(&self, path: &'b str) -> color_eyre::Result<&'b str> {
+        let prefix = &self.paths.strip_prefix;
+        path.strip_prefix(prefix.as_str()).ok_or_else(|| {
+            eyre!(
+                "{} doesn't start with the prefix {:?} of the project, \
+                 was the PKI generated with a different prefix?",
+                path,
+                prefix
+            )
+        })
+    }
+
+    /// Write the files into a new PK archive at `path`
+    fn write_files(&self, path: &Path, name: &str, files: &[Input]) -> color_eyre::Result<usize> {
+        let paths = self.paths;
+        let mut pk = PKHandle::open(path)
+            .wrap_err_with(|| format!("Failed to open PK {}", path.display()))?;
+
+        for file in files {
+            let relative_name = self.relative(&file.name)?;
+            let source = match self.lowercase {
+                true => win_join_nocase(&paths.proj_dir, relative_name),
+                false => win_join(&paths.proj_dir, relative_name),
+            };
+            let meta = match file.meta {
+                Some(meta) => self.check(file, &source, meta)?,
+                None => {
+                    self.computed.fetch_add(1, Ordering::Relaxed);
+                    self.describe(&file.name, &source).wrap_err_with(|| {
+                        format!("Failed to compute the metadata of {}", source.display())
+                    })?
+                }
+            };
+            let path = match file.is_compressed {
+                true => paths.cache_dir.join(meta.to_path()),
+                false => source,
+            };
+
+            self.bar.set_message(name.to_owned());
+            let mut writer = Writer { path: &path };
+            let (raw, compressed) = (meta.raw, meta.compressed);
+            pk.put_file(file.crc, &mut writer, raw, compressed, file.is_compressed)
+                .wrap_err_with(|| format!("Failed to add {} from {}", file.name, path.display()))?;
+            self.bar.inc(1);
+        }
+
+        pk.finish()
+            .wrap_err_with(|| format!("Failed to finish PK {}", name))?;
+        Ok(files.len())
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+/// The result of a [`run`]
+pub struct Stats {
+    /// The archives that were written
+    pub archives: Vec<String>,
+    /// The number of files that were packed
+    pub files: usize,
+    /// The number of files that were not in the manifest
+    pub computed: usize,
+    /// The number of files that didn't match the manifest
+    pub stale: usize,
+}
+
+/// Write (or verify) the PK archives selected by the filter
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<Stats> {
+    let paths = args.paths()?;
+
+    let output = &paths.cache_dir;
+    //let src_dir = args.dir.join(args.general.src);
+
+    let manifest_path = args.manifest_path(&paths);
+    let manifest = match manifest_path.is_file() {
+        true => {
+            let manifest = load_manifest(&manifest_path)?;
+            log::debug!("manifest has {} files", manifest.files.len());
+            Some(manifest)
+        }
+        false => {
+            log::warn!("No manifest, computing the metadata of all packed files");
+            None
+        }
+    };
+
+    let pack_index = load_pack_index(&args.pki_path(&paths))?;
+    log::info!("pack index has {} files", pack_index.files.len());
+
+    log::info!("patchdir: {}", output.display());
+
+    let filter = glob_set(&args.cmd.filter).context("failed to process filter glob")?;
+    let exclude = glob_set(&args.cmd.exclude).context("failed to process exclude glob")?;
+    let pack_prefix = format!("{}pack\\", paths.res_prefix_path());
+
+    let export: HashSet<usize> = pack_index
+        .archives
+        .iter()
+        .enumerate()
+        .filter_map(|(index, archive)| {
+            let key = pki::pack_key(&archive.path, &pack_prefix);
+            let is_match = |set: &GlobSet| {
+                set.is_match(&archive.path) || key.is_some_and(|key| set.is_match(key))
+            };
+            let included = args.cmd.filter.is_empty() || is_match(&filter);
+            (included && !is_match(&exclude)).then_some(index)
+        })
+        .collect();
+
+    if export.is_empty() {
+        log::warn!("No archives match the filters");
+    }
+    log::info!(
+        "Selected {} of {} archives:",
+        export.len(),
+        pack_index.archives.len()
+    );
+    for (index, archive) in pack_index.archives.iter().enumerate() {
+        if export.contains(&index) {
+            log::info!("    {}", archive.path);
+        }
+    }
+
+    if args.cmd.verify {
+        let Some(manifest) = &manifest else {
+            return Err(eyre!(
+                "`--verify` needs the manifest {}",
+                manifest_path.display()
+            ));
+        };
+        return verify::verify(
+            &paths,
+            manifest,
+            &pack_index,
+            &export,
+            args.project.lowercase,
+        );
+    }
+
+    // Group the files by archive, so that every archive can be written on its own
+    let mut jobs: BTreeMap<usize, Vec<Input>> = BTreeMap::new();
+    let mut missing: HashSet<u32> = pack_index
+        .files
+        .iter()
+        .filter(|(_, lookup)| export.contains(&(lookup.pack_file as usize)))
+        .map(|(crc, _)| *crc)
+        .collect();
+    for (name, (file, _)) in manifest.map(|m| m.files).unwrap_or_default() {
+        let crc = calculate_crc(name.as_bytes());
+        missing.remove(&crc);
+
+        if let Some(lookup) = pack_index.files.get(&crc) {
+            // File is to be packed
+            let pk_id = lookup.pack_file as usize;
+            log::debug!("{} should be packed into {}", name, pk_id);
+            if export.contains(&pk_id) {
+                // File is in a pack we want
+                jobs.entry(pk_id).or_default().push(Input {
+                    name,
+                    crc,
+                    meta: Some(file),
+                    is_compressed: pki::is_compressed(lookup),
+                });
+            }
+        }
+    }
+
+    // Files that are not in the manifest (yet) are taken from the `res` dir
+    if !missing.is_empty() {
+        log::info!("{} files are not in the manifest", missing.len());
+        let mut finder = Finder {
+            missing,
+            found: Vec::new(),
+        };
+        scan_dir(&mut finder, paths.res_prefix.clone(), &paths.res_dir, true);
+        for (name, crc) in finder.found {
+            let name = match args.project.lowercase {
+                true => paths.lowercase(&name),
+                false => name,
+            };
+            let lookup = &pack_index.files[&crc];
+            jobs.entry(lookup.pack_file as usize)
+                .or_default()
+                .push(Input {
+                    name,
+                    crc,
+                    meta: None,
+                    is_compressed: pki::is_compressed(lookup),
+                });
+        }
+        if !finder.missing.is_empty() {
+            log::warn!(
+                "{} files of the selected archives were not found",
+                finder.missing.len()
+            );
+        }
+    }
+
+    // The data is written in this order, so keep it independent of how the files were found
+    for files in jobs.values_mut() {
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    let total = jobs.values().map(Vec::len).sum::<usize>();
+    let bar = progress::bar(Some(total as u64), "files");
+    let packer = Packer {
+        paths: &paths,
+        pack_index: &pack_index,
+        lowercase: args.project.lowercase,
+        bar: &bar,
+        conv: Converter::for_project(args.project, paths.cache_dir.clone())?,
+        computed: AtomicUsize::new(0),
+        sd0: Mutex::new(HashMap::new()),
+        stale: args.cmd.stale,
+        check_hash: args.cmd.check_hash,
+        stale_files: AtomicUsize::new(0),
+    };
+
+    let threads = match args.cmd.jobs {
+        0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        n => n,
+    };
+    let threads = threads.min(jobs.len()).max(1);
+    log::debug!("Writing {} archives on {} threads", jobs.len(), threads);
+
+    let jobs: Vec<(usize, Vec<Input>)> = jobs.into_iter().collect();
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let mut results = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while let Some((pk_id, files)) = jobs.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        // Without `--keep-going`, don't start on new archives after an error
+                        if failed.load(Ordering::Relaxed) && !args.cmd.keep_going {
+                            break;
+                        }
+                        let result = packer.write_archive(*pk_id, files);
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        done.push((*pk_id, result));
+                    }
+                    done
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("pack worker panicked"))
+            .collect::<Vec<_>>()
+    });
+    bar.finish_and_clear();
+
+    results.sort_by_key(|(pk_id, _)| *pk_id);
+    let mut stats = Stats::default();
+    let mut errors = Vec::new();
+    for (pk_id, result) in results {
+        let archive = &pack_index.archives[pk_id].path;
+        match result.wrap_err_with(|| format!("Failed to write {}", archive)) {
+            Ok(files) => {
+                stats.files += files;
+                stats.archives.push(archive.clone());
+            }
+            Err(e) if args.cmd.keep_going => {
+                log::error!("{:?}", e);
+                errors.push(archive.clone());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    stats.computed = packer.computed.into_inner();
+    stats.stale = packer.stale_files.into_inner();
+
+    if !errors.is_empty() {
+        return Err(eyre!(
+            "Failed to write {} of {} archives: {}",
+            errors.len(),
+            jobs.len(),
+            errors.join(", ")
+        ));
+    }
+
+    Ok(stats)
+}
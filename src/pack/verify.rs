@@ -0,0 +1,196 @@
+//! Checks the PK archives against the package index and the manifest
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{self, BufReader},
+};
+
+use assembly_pack::{
+    common::{CRCTreeNode, FileMetaPair},
+    crc::calculate_crc,
+    md5::{io::IOSum, MD5Sum},
+    pk::{file::PKEntryData, reader::PackFile},
+    pki::core::PackIndexFile,
+    txt::Manifest,
+};
+use color_eyre::eyre::eyre;
+
+use crate::{pki::is_compressed, progress, win_join, win_join_nocase, Paths};
+
+use super::Stats;
+
+type Reader = PackFile<BufReader<File>>;
+
+/// Read the entries of a PK archive
+fn read_entries(mut pk: Reader) -> io::Result<(Reader, BTreeMap<u32, PKEntryData>)> {
+    pk.check_magic()?;
+    let header = pk.get_header()?;
+    let mut accessor = pk.get_entry_accessor(header.file_list_base_addr)?;
+    let entries = accessor.read_all()?;
+    Ok((accessor.into_inner(), entries))
+}
+
+/// Hash the data of an entry as it is stored in the archive
+fn hash_data(pk: &mut Reader, crc: u32, data: PKEntryData) -> io::Result<MD5Sum> {
+    let entry = CRCTreeNode {
+        crc,
+        left: -1,
+        right: -1,
+        data,
+    };
+    let mut reader = IOSum::new(pk.get_file_stream(entry));
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.digest())
+}
+
+/// Compare a single entry with the manifest, returning the problems
+fn check_entry(
+    pk: &mut Reader,
+    crc: u32,
+    entry: &PKEntryData,
+    meta: &FileMetaPair,
+    compressed: bool,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    if entry.orig_file_size != meta.raw.size || entry.orig_file_hash != meta.raw.hash {
+        problems.push(format!(
+            "raw {},{} instead of {}",
+            entry.orig_file_size, entry.orig_file_hash, meta.raw
+        ));
+    }
+    if entry.compr_file_size != meta.compressed.size
+        || entry.compr_file_hash != meta.compressed.hash
+    {
+        problems.push(format!(
+            "compressed {},{} instead of {}",
+            entry.compr_file_size, entry.compr_file_hash, meta.compressed
+        ));
+    }
+    if (entry.is_compressed & 0xFF > 0) != compressed {
+        problems.push(format!(
+            "is_compressed is {}, but the PKI says {}",
+            entry.is_compressed, compressed
+        ));
+    }
+    problems.extend(check_data(pk, crc, entry));
+    problems
+}
+
+/// Compare the data of an entry with the hash in the entry itself
+fn check_data(pk: &mut Reader, crc: u32, entry: &PKEntryData) -> Option<String> {
+    let expected = match entry.is_compressed & 0xFF > 0 {
+        true => entry.compr_file_hash,
+        false => entry.orig_file_hash,
+    };
+    match hash_data(pk, crc, *entry) {
+        Ok(hash) if hash == expected => None,
+        Ok(hash) => Some(format!("data has hash {} instead of {}", hash, expected)),
+        Err(e) => Some(format!("failed to read data: {}", e)),
+    }
+}
+
+/// Check the selected archives, failing with a report of all problems
+pub(super) fn verify(
+    paths: &Paths,
+    manifest: &Manifest,
+    pack_index: &PackIndexFile,
+    export: &HashSet<usize>,
+    lowercase: bool,
+) -> color_eyre::Result<Stats> {
+    let names: BTreeMap<u32, (&String, &FileMetaPair)> = manifest
+        .files
+        .iter()
+        .map(|(name, (meta, _))| (calculate_crc(name.as_bytes()), (name, meta)))
+        .collect();
+
+    let mut stats = Stats::default();
+    let mut problems = Vec::new();
+    let mut unverifiable = Vec::new();
+
+    let mut selected: Vec<usize> = export.iter().copied().collect();
+    selected.sort_unstable();
+    let bar = progress::bar(Some(selected.len() as u64), "archives");
+    for pk_id in selected {
+        let archive = &pack_index.archives[pk_id].path;
+        bar.set_message(archive.clone());
+        let relative = archive.strip_prefix(&paths.strip_prefix).unwrap_or(archive);
+        let path = match lowercase {
+            true => win_join_nocase(&paths.proj_dir, relative),
+            false => win_join(&paths.proj_dir, relative),
+        };
+        log::info!("Verifying PK {}", path.display());
+
+        let opened = File::open(&path).map(|f| PackFile::open(BufReader::new(f)));
+        let (mut pk, mut entries) = match opened.and_then(read_entries) {
+            Ok(pk) => pk,
+            Err(e) => {
+                problems.push(format!("{}: failed to read: {}", archive, e));
+                bar.inc(1);
+                continue;
+            }
+        };
+
+        let expected = pack_index
+            .files
+            .iter()
+            .filter(|(_, lookup)| lookup.pack_file as usize == pk_id);
+        for (crc, lookup) in expected {
+            let Some((name, meta)) = names.get(crc) else {
+                // `pack` computes the metadata of files that are not in the manifest,
+                // so only the data can be checked, against the entry itself
+                match entries.remove(crc) {
+                    Some(entry) => {
+                        if let Some(problem) = check_data(&mut pk, *crc, &entry) {
+                            problems.push(format!("{}: CRC {:08x}: {}", archive, crc, problem));
+                        }
+                        unverifiable.push(format!("{}: CRC {:08x}", archive, crc));
+                    }
+                    None => problems.push(format!("{}: CRC {:08x} is missing", archive, crc)),
+                }
+                continue;
+            };
+            let Some(entry) = entries.remove(crc) else {
+                problems.push(format!("{}: {} is missing", archive, name));
+                continue;
+            };
+            let compressed = is_compressed(lookup);
+            for problem in check_entry(&mut pk, *crc, &entry, meta, compressed) {
+                problems.push(format!("{}: {}: {}", archive, name, problem));
+            }
+            stats.files += 1;
+        }
+        for crc in entries.keys() {
+            match names.get(crc) {
+                Some((name, _)) => problems.push(format!("{}: unexpected {}", archive, name)),
+                None => problems.push(format!("{}: unexpected CRC {:08x}", archive, crc)),
+            }
+        }
+        stats.archives.push(archive.clone());
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+
+    if !unverifiable.is_empty() {
+        let mut report = String::new();
+        for entry in &unverifiable {
+            report.push_str(&format!("\n\t{}", entry));
+        }
+        log::warn!(
+            "{} entries are in the archives but not in the manifest, only their data was checked:{}",
+            unverifiable.len(),
+            report
+        );
+    }
+    if !problems.is_empty() {
+        let mut report = String::new();
+        for problem in &problems {
+            report.push_str(&format!("\n\t{}", problem));
+        }
+        return Err(eyre!(
+            "Found {} problems in the PK archives:{}",
+            problems.len(),
+            report
+        ));
+    }
+    Ok(stats)
+}
@@ -0,0 +1,135 @@
+//! This tool generates the files that point a client
+//! at the patch server, i.e. the `boot.cfg`, `version.txt`
+//! and the version index that lists the manifests.
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use argh::FromArgs;
+use assembly_pack::{
+    md5::{self, MD5Sum},
+    txt::{FileLine, Manifest, VersionLine},
+};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Help,
+};
+use indexmap::IndexMap;
+
+use crate::{
+    cache::{write_manifest, Converter},
+    config::PatcherConfig,
+    ProjectArgs,
+};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// generate the boot config and version index files
+#[argh(subcommand, name = "patcher")]
+pub struct Args {}
+
+fn boot_entries(cfg: &PatcherConfig, dir: &str) -> IndexMap<String, String> {
+    let auth_server = cfg.auth_server.as_deref().unwrap_or(&cfg.server);
+    let mut entries = IndexMap::new();
+    let mut set = |key: &str, value: String| {
+        entries.insert(key.to_string(), value);
+    };
+    set("SERVERNAME", format!("0:{}", cfg.server_name));
+    set("PATCHSERVERIP", format!("0:{}", cfg.server));
+    set("AUTHSERVERIP", format!("0:{}", auth_server));
+    set("PATCHSERVERPORT", format!("1:{}", cfg.port));
+    set("LOGGING", "1:0".to_string());
+    set("DATACENTERID", "1:150".to_string());
+    set("CPCODE", "1:89164".to_string());
+    set("AKAMAIDLM", "7:0".to_string());
+    set("PATCHSERVERDIR", format!("0:{}", dir));
+    set("UGCUSE3DSERVICES", "7:1".to_string());
+    set("UGCSERVERIP", format!("0:{}", cfg.server));
+    set("UGCSERVERDIR", "0:3dservices".to_string());
+    set("LOCALE", format!("0:{}", cfg.locale));
+    set("TRACK_DSK_USAGE", "7:1".to_string());
+    for (key, value) in &cfg.extra {
+        entries.insert(key.clone(), value.clone());
+    }
+    entries
+}
+
+fn write_boot_cfg(entries: &IndexMap<String, String>, path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (key, value) in entries {
+        writeln!(writer, "{}={},", key, value)?;
+    }
+    writer.flush()
+}
+
+fn write_version(version: &VersionLine, path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "[version]")?;
+    writeln!(writer, "{}", version)?;
+    writer.flush()
+}
+
+/// Get the index line for a manifest, making sure its sd0 file is in the cache
+fn index_line(conv: &Converter, output: &Path, name: &str) -> color_eyre::Result<FileLine> {
+    let path = output.join(name);
+    let raw = md5::md5sum(&path).wrap_err_with(|| format!("Failed to check {}", path.display()))?;
+    let (line, _) = conv
+        .find_or_convert(name, &path, raw)
+        .wrap_err_with(|| format!("Failed to convert {}", path.display()))?;
+    let linesum = MD5Sum::compute(&format!("{name},{line}"));
+    Ok((line, linesum))
+}
+
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
+    let paths = args.paths()?;
+    let Some(patcher) = args.project.patcher.as_ref() else {
+        return Err(eyre!("Project {:?} has no patcher config", args.name)).with_suggestion(|| {
+            format!(
+                "Add a `[project.{}.patcher]` section to LUpdate.toml",
+                args.name
+            )
+        });
+    };
+
+    let output = &paths.cache_dir;
+
+    let mf_name = &args.project.manifest;
+    let manifest_path = output.join(mf_name).with_extension("txt");
+    log::info!("manifest: {}", manifest_path.display());
+    let manifest = Manifest::from_file(&manifest_path)
+        .wrap_err_with(|| format!("Failed to load {}", manifest_path.display()))?;
+    let version = manifest.version;
+
+    let version_path = output.join("version.txt");
+    log::info!("Writing version to {}", version_path.display());
+    write_version(&version, &version_path).context("Failed to write version file")?;
+
+    let manifests = match patcher.manifests.is_empty() {
+        true => std::slice::from_ref(mf_name),
+        false => patcher.manifests.as_slice(),
+    };
+    let conv = Converter::for_project(args.project, output.clone())?;
+    let mut files = BTreeMap::new();
+    for mf in manifests {
+        let file_name = mf.with_extension("txt");
+        let name = file_name
+            .to_str()
+            .ok_or_else(|| eyre!("Manifest name {} is not UTF-8", file_name.display()))?;
+        let line = index_line(&conv, output, name)?;
+        files.insert(name.to_string(), line);
+    }
+    let index_path = output.join(&patcher.index).with_extension("txt");
+    write_manifest(&Manifest { version, files }, &index_path)
+        .context("Failed to write version index")?;
+
+    let cache_key = args.project.key.as_deref().unwrap_or(args.name);
+    let dir = patcher.dir.as_deref().unwrap_or(cache_key);
+    let boot_path = output.join(&patcher.boot);
+    log::info!("Writing boot config to {}", boot_path.display());
+    write_boot_cfg(&boot_entries(patcher, dir), &boot_path)
+        .context("Failed to write boot config")?;
+
+    Ok(())
+}
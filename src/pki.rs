@@ -1,41 +1,46 @@
 use argh::FromArgs;
 use assembly_pack::{
-    pki::{self, gen::Config, writer::write_pki_file},
+    pki::{
+        self,
+        core::{FileRef, PackIndexFile},
+        gen::{ArgEffect, Config},
+        writer::write_pki_file,
+    },
     txt::gen::{push_command, Command, DirSpec},
 };
 use color_eyre::eyre::Context;
 use indexmap::IndexMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
     ffi::OsStr,
     fs::File,
-    io::{BufRead, BufReader, BufWriter},
+    io::{BufRead, BufReader, BufWriter, Write},
 };
 
-use crate::ProjectArgs;
+use crate::{win_join, win_join_nocase, ProjectArgs};
 
-#[derive(FromArgs, PartialEq, Debug)]
+#[derive(FromArgs, PartialEq, Debug, Clone)]
 /// generate a PKI file from a directory tree
 #[argh(subcommand, name = "pki")]
 pub struct Args {}
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
 pub struct PackConfig {
     #[serde(default)]
-    compress: bool,
-    #[serde(default)]
-    dirs: Vec<String>,
-    #[serde(default)]
-    files: Vec<String>,
-    #[serde(default)]
-    exclude_files: Vec<String>,
-    #[serde(default)]
-    exclude_dirs: Vec<String>,
+    pub compress: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_files: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_dirs: Vec<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Cfg {
-    pack: IndexMap<String, PackConfig>,
+    pub pack: IndexMap<String, PackConfig>,
 }
 
 fn hidden_glob(filename: &str) -> Option<DirSpec> {
@@ -51,6 +56,50 @@ fn hidden_glob(filename: &str) -> Option<DirSpec> {
     None
 }
 
+/// Get the key of a pack config from the path of an archive
+///
+/// This is the inverse of the `pack\\{key}.pk` name in [`process_cfg`].
+pub(crate) fn pack_key<'a>(archive: &'a str, pack_prefix: &str) -> Option<&'a str> {
+    let name = archive.strip_prefix(pack_prefix)?;
+    Some(name.strip_suffix(".pk").unwrap_or(name))
+}
+
+/// Whether a file of the PKI is stored compressed in its archive
+pub(crate) fn is_compressed(lookup: &FileRef) -> bool {
+    lookup.category & 0xFF > 0
+}
+
+/// Warn about the files and dirs of the pack config that don't exist
+///
+/// With `lowercase`, they are matched case-insensitively and take the case on disk,
+/// because the generator scans the dirs with the case from the config.
+fn resolve_args(config: &mut Config, lowercase: bool) {
+    let root = &config.directory;
+    for pack_file in &mut config.pack_files {
+        for arg in &mut pack_file.args {
+            let real = match lowercase {
+                true => win_join_nocase(root, &arg.name),
+                false => win_join(root, &arg.name),
+            };
+            if !real.exists() {
+                if arg.effect == ArgEffect::Include {
+                    log::warn!(
+                        "{}: {} matches nothing in {}",
+                        pack_file.name,
+                        arg.name,
+                        root.display()
+                    );
+                }
+                continue;
+            }
+            if let (true, Ok(relative)) = (lowercase, real.strip_prefix(root)) {
+                let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+                arg.name = parts.join("\\");
+            }
+        }
+    }
+}
+
 fn process_cfg(config: &mut Config, cfg: Cfg) {
     for (k, v) in cfg.pack {
         let cmd = Command::Pack {
@@ -99,8 +148,9 @@ fn process_cfg(config: &mut Config, cfg: Cfg) {
     }
 }
 
-pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
-    let paths = args.paths();
+/// Generate the PKI file of the project and return its contents
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<PackIndexFile> {
+    let paths = args.paths()?;
     log::debug!("{:#?}", paths);
 
     let cfg_path = paths.proj_dir.join(&args.project.config);
@@ -113,7 +163,7 @@ pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
 
     let mut config = pki::gen::Config {
         prefix: paths.res_prefix_path(),
-        directory: paths.res_dir,
+        directory: paths.res_dir.clone(),
         output,
         manifest,
         pack_files: vec![],
@@ -136,17 +186,26 @@ pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<()> {
         }
     }
 
+    resolve_args(&mut config, args.project.lowercase);
+
     let output = config.output.clone();
-    let pki = config.run();
+    let mut pki = config.run();
+    if args.project.lowercase {
+        for archive in &mut pki.archives {
+            archive.path = paths.lowercase(&archive.path);
+        }
+    }
 
-    log::info!("number of archives: {}", pki.archives.len());
-    log::info!("number of files: {}", pki.files.len());
     log::info!("Writing to {}", output.display());
 
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent).wrap_err("Failed to create output dir")?;
+    }
     let file = File::create(&output).context("Failed to create output file")?;
 
     let mut writer = BufWriter::new(file);
     write_pki_file(&mut writer, &pki).context("Failed to write PKI file")?;
+    writer.flush().context("Failed to write PKI file")?;
 
-    Ok(())
+    Ok(pki)
 }
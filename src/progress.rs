@@ -0,0 +1,40 @@
+//! Progress bars for long running commands
+//!
+//! The bars are drawn to stderr and stay hidden if it is not a terminal.
+use std::sync::OnceLock;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+
+/// The progress bars of this process
+///
+/// Log output needs to be routed through this (e.g. with `indicatif-log-bridge`)
+/// so that it doesn't garble the bars.
+pub fn multi() -> &'static MultiProgress {
+    MULTI.get_or_init(MultiProgress::new)
+}
+
+/// Create a bar for `len` items of `unit`, or a spinner if the length is not known
+pub(crate) fn bar(len: Option<u64>, unit: &str) -> ProgressBar {
+    let multi = multi();
+    if multi.is_hidden() {
+        return ProgressBar::hidden();
+    }
+    let (bar, template) = match len {
+        Some(len) => (
+            ProgressBar::new(len),
+            format!(
+                "[{{elapsed_precise}}] {{wide_bar}} {{pos}}/{{len}} {unit} (ETA {{eta}}) {{msg}}"
+            ),
+        ),
+        None => (
+            ProgressBar::no_length(),
+            format!("{{spinner}} [{{elapsed_precise}}] {{pos}} {unit} {{msg}}"),
+        ),
+    };
+    let style = ProgressStyle::with_template(&template)
+        .expect("valid progress template")
+        .progress_chars("=> ");
+    multi.add(bar.with_style(style))
+}
@@ -0,0 +1,166 @@
+//! This tool summarizes the content of a project from its
+//! manifest and PKI, to help with the pack layout and to
+//! find what takes up the most space.
+use std::{cmp::Reverse, collections::BTreeMap, path::PathBuf};
+
+use argh::FromArgs;
+use assembly_pack::{common::FileMetaPair, crc::calculate_crc};
+
+use crate::{pki::is_compressed, ProjectArgs};
+
+#[derive(FromArgs, PartialEq, Debug, Clone)]
+/// summarize the sizes of the files in the manifest and PK archives
+#[argh(subcommand, name = "stats")]
+pub struct Args {
+    /// path to the manifest (default: the project manifest)
+    #[argh(option, short = 'm')]
+    pub manifest: Option<PathBuf>,
+
+    /// path to the PKI file (default: the project PKI in the cache dir, if it exists)
+    #[argh(option)]
+    pub pki: Option<PathBuf>,
+
+    /// number of largest files to list
+    #[argh(option, default = "10")]
+    pub top: usize,
+
+    /// number of directories below the project prefix to group by
+    #[argh(option, default = "2")]
+    pub depth: usize,
+}
+
+/// The sizes of a group of files
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    /// The number of files
+    pub files: usize,
+    /// The sum of the raw sizes
+    pub raw: u64,
+    /// The sum of the sizes of the sd0 files
+    pub compressed: u64,
+}
+
+impl Totals {
+    fn add(&mut self, meta: &FileMetaPair) {
+        self.files += 1;
+        self.raw += u64::from(meta.raw.size);
+        self.compressed += u64::from(meta.compressed.size);
+    }
+
+    /// The compressed size as a fraction of the raw size
+    pub fn ratio(&self) -> f64 {
+        match self.raw {
+            0 => 1.0,
+            raw => self.compressed as f64 / raw as f64,
+        }
+    }
+}
+
+/// A PK archive of the PKI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Archive {
+    /// The path of the archive
+    pub path: String,
+    /// The number of files of the manifest that are packed into it
+    pub files: usize,
+    /// The bytes of those files as they are stored in the archive
+    pub content: u64,
+    /// The size of the archive, if it is in the manifest
+    pub size: Option<u64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+/// The result of a [`run`]
+pub struct Stats {
+    /// All files of the manifest
+    pub total: Totals,
+    /// The files by their (lowercase) extension
+    pub by_extension: BTreeMap<String, Totals>,
+    /// The files by their directory, up to `--depth` levels below the prefix
+    pub by_dir: BTreeMap<String, Totals>,
+    /// The largest files by raw size
+    pub largest: Vec<(String, FileMetaPair)>,
+    /// The archives of the PKI
+    pub archives: Vec<Archive>,
+}
+
+fn extension(path: &str) -> String {
+    let name = path.rsplit('\\').next().unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.to_lowercase(),
+        _ => "(none)".to_string(),
+    }
+}
+
+/// The first `depth` directories of a path below the prefix
+fn directory(path: &str, strip_prefix: &str, depth: usize) -> String {
+    let relative = path.strip_prefix(strip_prefix).unwrap_or(path);
+    let parts: Vec<&str> = relative.split('\\').collect();
+    let dirs = &parts[..parts.len() - 1];
+    match &dirs[..depth.min(dirs.len())] {
+        [] => ".".to_string(),
+        dirs => dirs.join("\\"),
+    }
+}
+
+/// Summarize the manifest and PKI
+pub fn run(args: ProjectArgs<Args>) -> color_eyre::Result<Stats> {
+    let paths = args.paths()?;
+    let (manifest, pack_index) = args.load_manifest_and_pki(
+        &paths,
+        args.cmd.manifest.as_deref(),
+        args.cmd.pki.as_deref(),
+    )?;
+
+    let mut stats = Stats::default();
+    for (path, (meta, _)) in &manifest.files {
+        stats.total.add(meta);
+        stats
+            .by_extension
+            .entry(extension(path))
+            .or_default()
+            .add(meta);
+        let dir = directory(path, &paths.strip_prefix, args.cmd.depth);
+        stats.by_dir.entry(dir).or_default().add(meta);
+    }
+
+    let mut largest: Vec<_> = manifest
+        .files
+        .iter()
+        .map(|(path, (meta, _))| (path.clone(), *meta))
+        .collect();
+    largest.sort_by_key(|(path, meta)| (Reverse(meta.raw.size), path.clone()));
+    largest.truncate(args.cmd.top);
+    stats.largest = largest;
+
+    if let Some(pack_index) = &pack_index {
+        stats.archives = pack_index
+            .archives
+            .iter()
+            .map(|archive| Archive {
+                path: archive.path.clone(),
+                files: 0,
+                content: 0,
+                size: manifest
+                    .files
+                    .get(&archive.path)
+                    .map(|(meta, _)| u64::from(meta.raw.size)),
+            })
+            .collect();
+        for (path, (meta, _)) in &manifest.files {
+            let Some(lookup) = pack_index.files.get(&calculate_crc(path.as_bytes())) else {
+                continue;
+            };
+            let Some(archive) = stats.archives.get_mut(lookup.pack_file as usize) else {
+                continue;
+            };
+            archive.files += 1;
+            archive.content += match is_compressed(lookup) {
+                true => u64::from(meta.compressed.size),
+                false => u64::from(meta.raw.size),
+            };
+        }
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,101 @@
+//! Packing the same inputs must produce byte-identical PK archives
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use assembly_pack::md5::{md5sum, MD5Sum};
+use lupdate::{cache, pack, pki, Config, ProjectArgs};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/reproducible");
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        match entry.file_type()?.is_dir() {
+            true => copy_dir(&entry.path(), &target)?,
+            false => fs::copy(entry.path(), &target).map(drop)?,
+        }
+    }
+    Ok(())
+}
+
+/// A copy of the fixture in a fresh temporary directory
+fn workspace(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "lupdate-reproducible-{}-{}",
+        std::process::id(),
+        name
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    copy_dir(Path::new(FIXTURE), &dir).unwrap();
+    dir
+}
+
+fn args<'a, A>(dir: &Path, config: &'a Config, cmd: A) -> ProjectArgs<'a, A> {
+    let project = &config.project["client"];
+    ProjectArgs::new(
+        dir.to_owned(),
+        config.general.clone(),
+        project,
+        "client",
+        cmd,
+    )
+}
+
+/// Run `pki`, optionally `cache`, and `pack` with `cmd` in the workspace
+fn build(dir: &Path, with_cache: bool, cmd: pack::Args) -> color_eyre::Result<()> {
+    let config = Config::from_file(dir.join("LUpdate.toml"))?;
+    pki::run(args(dir, &config, pki::Args {}))?;
+    if with_cache {
+        cache::run(args(dir, &config, cache::Args::default()))?;
+    }
+    pack::run(args(dir, &config, cmd))?;
+    Ok(())
+}
+
+/// The hashes of the PK archives of the workspace
+fn pk_hashes(dir: &Path) -> Vec<(String, MD5Sum)> {
+    let pack_dir = dir.join("src/client/res/pack");
+    let mut hashes: Vec<_> = fs::read_dir(&pack_dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, md5sum(&path).unwrap().hash)
+        })
+        .collect();
+    hashes.sort_by(|a, b| a.0.cmp(&b.0));
+    hashes
+}
+
+#[test]
+fn pack_twice_is_identical() {
+    let first = workspace("first");
+    build(&first, true, pack::Args::default()).unwrap();
+    let expected = pk_hashes(&first);
+    assert_eq!(expected.len(), 2);
+
+    // Packing again in the same workspace
+    build(&first, false, pack::Args::default()).unwrap();
+    assert_eq!(pk_hashes(&first), expected);
+
+    // In another place, without a manifest, in parallel and over larger old archives
+    let second = workspace("second");
+    let pack_dir = second.join("src/client/res/pack");
+    fs::create_dir_all(&pack_dir).unwrap();
+    for (name, _) in &expected {
+        fs::write(pack_dir.join(name), vec![0xAB; 64 * 1024]).unwrap();
+    }
+    let cmd = pack::Args {
+        jobs: 0,
+        ..Default::default()
+    };
+    build(&second, false, cmd).unwrap();
+    assert_eq!(pk_hashes(&second), expected);
+
+    fs::remove_dir_all(&first).unwrap();
+    fs::remove_dir_all(&second).unwrap();
+}